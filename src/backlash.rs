@@ -0,0 +1,170 @@
+//! Backlash compensation for positioning moves.
+//!
+//! When a positioning move reverses the direction of travel, the configured backlash distance is
+//! added to the target sent to the chip, so the slack of the mechanics is taken up before the load
+//! moves. The accumulated correction is an offset between the user coordinates and the chip
+//! coordinates, which is added to position writes and removed from position reads, so `XACTUAL`,
+//! `XTARGET`, `XLATCH` and `X_COMPARE` stay in user coordinates.
+//!
+//! The direction of a move is taken from the previous `XTARGET`, so only positioning moves are
+//! tracked. Stopping a move with [`Tmc5130::stop`] or [`Tmc5130::stop_positioning`] writes the stop
+//! position in chip coordinates and is not taken for a reversal. After a velocity mode move, [`Tmc5130::set_backlash_direction`] tells the compensation
+//! in which direction the slack was last taken up.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::Address;
+use crate::{Direction, Tmc5130};
+
+/// State of the backlash compensation.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Backlash {
+    /// The backlash of the mechanics, in microsteps.
+    pub distance: u32,
+    /// Chip coordinates minus user coordinates.
+    pub offset: i32,
+    /// The direction of the last move, unknown until the first move.
+    pub direction: Option<Direction>,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Enables backlash compensation of `distance` microsteps, or disables it with `None`.
+    ///
+    /// The compensation starts without an offset and an unknown direction, so the first move is
+    /// never compensated. Disabling the compensation leaves the chip coordinates in place, so a
+    /// non-zero offset shows up in the reported positions afterwards.
+    pub fn set_backlash_compensation(&mut self, distance: Option<u32>) {
+        self.backlash = distance.map(|distance| Backlash { distance, offset: 0, direction: None });
+    }
+
+    /// The current state of the backlash compensation.
+    pub fn backlash(&self) -> Option<Backlash> {
+        self.backlash
+    }
+
+    /// Sets the direction in which the slack was last taken up, for instance after homing or a
+    /// velocity mode move.
+    pub fn set_backlash_direction(&mut self, direction: Direction) {
+        if let Some(backlash) = self.backlash.as_mut() {
+            backlash.direction = Some(direction);
+        }
+    }
+
+    /// Drops the offset between user and chip coordinates, used when the position is redefined.
+    pub(crate) fn reset_backlash_offset(&mut self) {
+        if let Some(backlash) = self.backlash.as_mut() {
+            backlash.offset = 0;
+        }
+    }
+
+    /// Maps a position write from user to chip coordinates.
+    ///
+    /// An `XTARGET` write reversing the direction of travel grows the offset by the backlash
+    /// distance first.
+    pub(crate) fn backlash_write(&mut self, addr: Address, data: u32) -> u32 {
+        let previous_target = *self.shadow.xtarget();
+        let backlash = match self.backlash.as_mut() {
+            Some(backlash) => backlash,
+            None => return data,
+        };
+        if addr == Address::XTARGET {
+            let target = data as i32;
+            let previous = previous_target.get().wrapping_sub(backlash.offset);
+            let direction = match target.cmp(&previous) {
+                core::cmp::Ordering::Less => Some(Direction::Negative),
+                core::cmp::Ordering::Greater => Some(Direction::Positive),
+                core::cmp::Ordering::Equal => None,
+            };
+            if let Some(direction) = direction {
+                if backlash.direction.is_some_and(|last| last != direction) {
+                    backlash.offset = backlash.offset.wrapping_add(direction.signum() * backlash.distance as i32);
+                }
+                backlash.direction = Some(direction);
+            }
+        }
+        match addr {
+            Address::XACTUAL | Address::XTARGET | Address::X_COMPARE => (data as i32).wrapping_add(backlash.offset) as u32,
+            _ => data,
+        }
+    }
+
+    /// Maps a position read from chip to user coordinates.
+    pub(crate) fn backlash_read(&self, addr: Address, data: u32) -> u32 {
+        match (addr, self.backlash) {
+            (Address::XACTUAL | Address::XTARGET | Address::XLATCH, Some(backlash)) => {
+                (data as i32).wrapping_sub(backlash.offset) as u32
+            }
+            _ => data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+
+    use crate::mock_peripherals::{datagram, driver, read, read_register, write, VZERO};
+    use crate::reg::{self, Address};
+    use crate::Direction;
+
+    fn xtarget(position: i32) -> reg::XTARGET {
+        let mut xtarget = reg::XTARGET::default();
+        xtarget.set(position);
+        xtarget
+    }
+
+    #[test]
+    fn reversal_offsets_target_and_position() {
+        let (mut driver, mut spi) = driver(&[
+            write(Address::XTARGET, 100, 0),
+            write(Address::XTARGET, 40, 0),
+            read(Address::XACTUAL, 0),
+            datagram(Address::XACTUAL as u8, 0, 0, 40),
+            write(Address::XTARGET, 80, 0),
+        ]);
+        driver.set_backlash_compensation(Some(10));
+        // The first move sets the direction without compensation.
+        driver.write_register(xtarget(100)).unwrap();
+        driver.write_register(xtarget(50)).unwrap();
+        let backlash = driver.backlash().unwrap();
+        assert_eq!((backlash.offset, backlash.direction), (-10, Some(Direction::Negative)));
+        let (_, xactual) = driver.read_register::<reg::XACTUAL>().unwrap();
+        assert_eq!(xactual.get(), 50);
+        driver.write_register(xtarget(80)).unwrap();
+        assert_eq!(driver.backlash().unwrap().offset, 0);
+        spi.done();
+    }
+
+    #[test]
+    fn known_direction_compensates_first_move() {
+        let (mut driver, mut spi) = driver(&[write(Address::XTARGET, 110, 0)]);
+        driver.set_backlash_compensation(Some(10));
+        driver.set_backlash_direction(Direction::Negative);
+        driver.write_register(xtarget(100)).unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn stopping_mid_move_is_not_a_reversal() {
+        let mut datagrams = vec![write(Address::XTARGET, 1000, 0), write(Address::VMAX, 0, 0)];
+        datagrams.extend(read_register(Address::RAMP_STAT, VZERO));
+        datagrams.extend(read_register(Address::XACTUAL, 400));
+        datagrams.extend([write(Address::XTARGET, 400, 0), write(Address::VMAX, 5000, 0)]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_backlash_compensation(Some(10));
+        driver.set_backlash_direction(Direction::Positive);
+        let mut vmax = reg::VMAX::default();
+        vmax.set(5000);
+        driver.shadow.set_state(vmax.into());
+        driver.write_register(xtarget(1000)).unwrap();
+        driver.stop_positioning(&mut NoopDelay::new(), 10, 100).unwrap();
+        let backlash = driver.backlash().unwrap();
+        assert_eq!((backlash.offset, backlash.direction), (0, Some(Direction::Positive)));
+        assert_eq!(driver.shadow().xtarget().get(), 400);
+        spi.done();
+    }
+}
@@ -0,0 +1,306 @@
+//! Validated chopper configuration for `CHOPCONF`.
+//!
+//! [`ChopperConfig`] is built in the effective units of the datasheet rather than the register
+//! encoding, and is checked against its rules when built:
+//!
+//! * `TOFF` 0 disables the driver, and `TOFF` 1 may only be used with `TBL` of at least 2.
+//! * In SpreadCycle, hysteresis start (1 to 8) plus hysteresis end (-3 to 12) must not exceed 16.
+//! * In constant off time mode, the fast decay time is 0 to 15, and the sine wave offset -3 to 12.
+//!
+//! Only the chopper fields of `CHOPCONF` are set, the microstep resolution, interpolation and the
+//! other fields are kept.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Error, Tmc5130};
+
+/// Blank time in clock cycles, indexed by `TBL`.
+const BLANK_TIME_CLOCKS: [u32; 4] = [16, 24, 36, 54];
+
+/// Reasons a chopper configuration is rejected.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChopperError {
+    /// `TOFF` is 0, which disables the driver, or above 15.
+    InvalidOffTime,
+    /// `TOFF` 1 requires a `TBL` of at least 2.
+    OffTimeTooShortForBlankTime,
+    /// `TBL` is above 3.
+    InvalidBlankTime,
+    /// The hysteresis start is outside of 1 to 8.
+    InvalidHysteresisStart,
+    /// The hysteresis end is outside of -3 to 12.
+    InvalidHysteresisEnd,
+    /// Hysteresis start plus hysteresis end exceeds 16.
+    HysteresisTooHigh,
+    /// The fast decay time is above 15.
+    InvalidFastDecayTime,
+    /// The sine wave offset is outside of -3 to 12.
+    InvalidSineOffset,
+}
+
+/// The chopper mode and its mode specific settings, in effective units.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ChopperMode {
+    /// SpreadCycle, `CHOPCONF::chm` low.
+    SpreadCycle {
+        /// Hysteresis start `HSTRT`, 1 to 8.
+        hysteresis_start: u8,
+        /// Hysteresis end `HEND`, -3 to 12.
+        hysteresis_end: i8,
+    },
+    /// Classic constant off time chopper, `CHOPCONF::chm` high.
+    ConstantOffTime {
+        /// Fast decay time `TFD` in multiples of 32 clock cycles, 0 to 15.
+        fast_decay_time: u8,
+        /// Sine wave offset `OFFSET`, -3 to 12.
+        sine_offset: i8,
+        /// Ends the fast decay phase on the current comparator, `CHOPCONF::disfdcc` low.
+        fast_decay_comparator: bool,
+    },
+}
+
+/// A validated chopper configuration.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChopperConfig {
+    toff: u8,
+    tbl: u8,
+    random_off_time: bool,
+    mode: ChopperMode,
+}
+
+/// Builder for a [`ChopperConfig`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ChopperConfigBuilder {
+    config: ChopperConfig,
+}
+
+impl ChopperConfig {
+    /// Starts a SpreadCycle configuration, with `TOFF` 3 and `TBL` 2 unless changed.
+    pub fn spread_cycle(hysteresis_start: u8, hysteresis_end: i8) -> ChopperConfigBuilder {
+        ChopperConfigBuilder::new(ChopperMode::SpreadCycle { hysteresis_start, hysteresis_end })
+    }
+
+    /// Starts a constant off time configuration, with `TOFF` 3 and `TBL` 2 unless changed.
+    pub fn constant_off_time(fast_decay_time: u8, sine_offset: i8) -> ChopperConfigBuilder {
+        ChopperConfigBuilder::new(ChopperMode::ConstantOffTime { fast_decay_time, sine_offset, fast_decay_comparator: true })
+    }
+
+    /// Reads the chopper configuration back from `CHOPCONF`.
+    ///
+    /// Fails if the register holds a configuration the rules do not allow.
+    pub fn from_chopconf(chopconf: reg::CHOPCONF) -> Result<Self, ChopperError> {
+        let mode = if chopconf.chm() {
+            ChopperMode::ConstantOffTime {
+                fast_decay_time: (chopconf.hstrt() | (chopconf.fd3() as u32) << 3) as u8,
+                sine_offset: chopconf.hend() as i8 - 3,
+                fast_decay_comparator: !chopconf.disfdcc(),
+            }
+        } else {
+            ChopperMode::SpreadCycle {
+                hysteresis_start: chopconf.hstrt() as u8 + 1,
+                hysteresis_end: chopconf.hend() as i8 - 3,
+            }
+        };
+        let config = Self {
+            toff: chopconf.toff() as u8,
+            tbl: chopconf.tbl() as u8,
+            random_off_time: chopconf.rndtf(),
+            mode,
+        };
+        config.validate()
+    }
+
+    /// The slow decay time setting `TOFF`.
+    pub fn off_time(&self) -> u8 {
+        self.toff
+    }
+
+    /// The blank time setting `TBL`.
+    pub fn blank_time(&self) -> u8 {
+        self.tbl
+    }
+
+    /// The chopper mode.
+    pub fn mode(&self) -> ChopperMode {
+        self.mode
+    }
+
+    /// The slow decay time in clock cycles, `24 + 32 * TOFF`.
+    pub fn off_time_clocks(&self) -> u32 {
+        24 + 32 * self.toff as u32
+    }
+
+    /// The blank time in clock cycles.
+    pub fn blank_time_clocks(&self) -> u32 {
+        BLANK_TIME_CLOCKS[self.tbl as usize]
+    }
+
+    /// An estimate of the chopper frequency at the given clock frequency, in Hz.
+    ///
+    /// A chopper cycle has two slow decay phases, and at least two blank times for the on and
+    /// fast decay phases. The actual frequency is lower when those phases take longer, which
+    /// depends on the motor and supply voltage.
+    pub fn chopper_frequency_hz(&self, clock_hz: u32) -> f32 {
+        clock_hz as f32 / (2 * self.off_time_clocks() + 2 * self.blank_time_clocks()) as f32
+    }
+
+    /// Sets the chopper fields of `chopconf`, keeping all others.
+    pub fn apply(&self, chopconf: reg::CHOPCONF) -> reg::CHOPCONF {
+        let mut chopconf = chopconf;
+        chopconf.set_toff(self.toff as u32);
+        chopconf.set_tbl(self.tbl as u32);
+        chopconf.set_rndtf(self.random_off_time);
+        match self.mode {
+            ChopperMode::SpreadCycle { hysteresis_start, hysteresis_end } => {
+                chopconf.set_chm(false);
+                chopconf.set_hstrt(hysteresis_start as u32 - 1);
+                chopconf.set_hend((hysteresis_end + 3) as u32);
+                chopconf.set_fd3(false);
+                chopconf.set_disfdcc(false);
+            }
+            ChopperMode::ConstantOffTime { fast_decay_time, sine_offset, fast_decay_comparator } => {
+                chopconf.set_chm(true);
+                chopconf.set_hstrt(fast_decay_time as u32 & 0b111);
+                chopconf.set_fd3(fast_decay_time & 0b1000 != 0);
+                chopconf.set_hend((sine_offset + 3) as u32);
+                chopconf.set_disfdcc(!fast_decay_comparator);
+            }
+        }
+        chopconf
+    }
+
+    fn validate(self) -> Result<Self, ChopperError> {
+        if self.toff == 0 || self.toff > 15 {
+            return Err(ChopperError::InvalidOffTime);
+        }
+        if self.tbl > 3 {
+            return Err(ChopperError::InvalidBlankTime);
+        }
+        if self.toff == 1 && self.tbl < 2 {
+            return Err(ChopperError::OffTimeTooShortForBlankTime);
+        }
+        match self.mode {
+            ChopperMode::SpreadCycle { hysteresis_start, hysteresis_end } => {
+                if !(1..=8).contains(&hysteresis_start) {
+                    return Err(ChopperError::InvalidHysteresisStart);
+                }
+                if !(-3..=12).contains(&hysteresis_end) {
+                    return Err(ChopperError::InvalidHysteresisEnd);
+                }
+                if hysteresis_start as i8 + hysteresis_end > 16 {
+                    return Err(ChopperError::HysteresisTooHigh);
+                }
+            }
+            ChopperMode::ConstantOffTime { fast_decay_time, sine_offset, .. } => {
+                if fast_decay_time > 15 {
+                    return Err(ChopperError::InvalidFastDecayTime);
+                }
+                if !(-3..=12).contains(&sine_offset) {
+                    return Err(ChopperError::InvalidSineOffset);
+                }
+            }
+        }
+        Ok(self)
+    }
+}
+
+impl ChopperConfigBuilder {
+    fn new(mode: ChopperMode) -> Self {
+        Self { config: ChopperConfig { toff: 3, tbl: 2, random_off_time: false, mode } }
+    }
+
+    /// Sets the slow decay time `TOFF`, 1 to 15.
+    pub fn off_time(mut self, toff: u8) -> Self {
+        self.config.toff = toff;
+        self
+    }
+
+    /// Sets the comparator blank time `TBL`, 0 to 3 for 16, 24, 36 or 54 clock cycles.
+    pub fn blank_time(mut self, tbl: u8) -> Self {
+        self.config.tbl = tbl;
+        self
+    }
+
+    /// Enables random modulation of the off time, `CHOPCONF::rndtf`.
+    pub fn random_off_time(mut self, enabled: bool) -> Self {
+        self.config.random_off_time = enabled;
+        self
+    }
+
+    /// Whether the current comparator can end the fast decay phase, in constant off time mode.
+    ///
+    /// Has no effect in SpreadCycle.
+    pub fn fast_decay_comparator(mut self, enabled: bool) -> Self {
+        if let ChopperMode::ConstantOffTime { fast_decay_comparator, .. } = &mut self.config.mode {
+            *fast_decay_comparator = enabled;
+        }
+        self
+    }
+
+    /// Validates the configuration.
+    pub fn build(self) -> Result<ChopperConfig, ChopperError> {
+        self.config.validate()
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Writes a chopper configuration, keeping the other fields of `CHOPCONF`.
+    pub fn set_chopper(&mut self, config: &ChopperConfig) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let (_, chopconf) = self.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+        self.write_register(config.apply(chopconf))
+    }
+
+    /// The estimated chopper frequency of the last known `CHOPCONF`, `None` if it does not hold a
+    /// valid configuration.
+    pub fn chopper_frequency_hz(&self) -> Option<f32> {
+        ChopperConfig::from_chopconf(*self.shadow.chopconf())
+            .ok()
+            .map(|config| config.chopper_frequency_hz(self.clock_hz))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spread_cycle_encoding() {
+        let config = ChopperConfig::spread_cycle(5, 0).off_time(3).blank_time(2).build().unwrap();
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_mres(4);
+        chopconf.set_intpol(true);
+        let chopconf = config.apply(chopconf);
+        assert_eq!(chopconf.hstrt(), 4);
+        assert_eq!(chopconf.hend(), 3);
+        assert_eq!(chopconf.mres(), 4);
+        assert!(chopconf.intpol());
+        assert_eq!(ChopperConfig::from_chopconf(chopconf), Ok(config));
+    }
+
+    #[test]
+    fn rejects_datasheet_violations() {
+        assert_eq!(ChopperConfig::spread_cycle(5, 0).off_time(0).build(), Err(ChopperError::InvalidOffTime));
+        assert_eq!(ChopperConfig::spread_cycle(5, 0).off_time(1).blank_time(1).build(), Err(ChopperError::OffTimeTooShortForBlankTime));
+        assert_eq!(ChopperConfig::spread_cycle(8, 9).build(), Err(ChopperError::HysteresisTooHigh));
+        assert_eq!(ChopperConfig::constant_off_time(16, 0).build(), Err(ChopperError::InvalidFastDecayTime));
+    }
+
+    #[test]
+    fn constant_off_time_encoding() {
+        let config = ChopperConfig::constant_off_time(13, -3).fast_decay_comparator(false).build().unwrap();
+        let chopconf = config.apply(reg::CHOPCONF::default());
+        assert!(chopconf.chm());
+        assert_eq!(chopconf.hstrt(), 5);
+        assert!(chopconf.fd3());
+        assert_eq!(chopconf.hend(), 0);
+        assert!(chopconf.disfdcc());
+        assert_eq!(ChopperConfig::from_chopconf(chopconf), Ok(config));
+    }
+}
@@ -0,0 +1,142 @@
+//! Position compare trigger output on DIAG1.
+//!
+//! The chip pulses the SWP_DIAG1 output whenever `XACTUAL` equals `X_COMPARE`, which is useful to
+//! trigger external equipment such as cameras at exact positions. The chip only compares against a
+//! single position, so a series of equally spaced triggers is produced by moving `X_COMPARE` to the
+//! next position once `XACTUAL` has passed the current one, see
+//! [`Tmc5130::poll_position_compare`].
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Error, Tmc5130};
+
+/// The driver type of the DIAG1 output while it signals position compare hits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CompareOutput {
+    /// Open collector output, active low.
+    OpenDrain,
+    /// Push pull output, active high.
+    PushPull,
+}
+
+/// A series of equally spaced position compare triggers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CompareSchedule {
+    /// The position currently armed in `X_COMPARE`.
+    pub next: i32,
+    /// Distance between two triggers, its sign is the direction of travel.
+    pub spacing: i32,
+    /// Triggers left in the series, including the armed one.
+    pub remaining: u32,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Selects the driver type of DIAG1 through `GCONF::diag1_poscomp_pushpull`.
+    ///
+    /// Other functions enabled on DIAG1 in `GCONF` are signalled on the same pin.
+    pub fn set_compare_output(&mut self, output: CompareOutput) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let (_, mut gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        gconf.set_diag1_poscomp_pushpull(output == CompareOutput::PushPull);
+        self.write_register(gconf)
+    }
+
+    /// Arms a single position compare trigger, cancelling any scheduled series.
+    pub fn arm_position_compare(&mut self, position: i32) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        self.compare_schedule = None;
+        self.write_register(reg::X_COMPARE(position as u32))
+    }
+
+    /// Schedules `count` triggers, starting at `start` and `spacing` microsteps apart.
+    ///
+    /// The first position is armed right away, the following ones by
+    /// [`Tmc5130::poll_position_compare`]. Fails with [`Error::OutOfRange`] if `spacing` is zero.
+    pub fn schedule_position_compare(&mut self, start: i32, spacing: i32, count: u32) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        if spacing == 0 {
+            return Err(Error::OutOfRange);
+        }
+        let status = self.write_register(reg::X_COMPARE(start as u32))?;
+        self.compare_schedule = (count > 0).then_some(CompareSchedule { next: start, spacing, remaining: count });
+        Ok(status)
+    }
+
+    /// The series of triggers in progress, `None` once it is complete or cancelled.
+    pub fn position_compare_schedule(&self) -> Option<CompareSchedule> {
+        self.compare_schedule
+    }
+
+    /// Cancels the series of triggers in progress.
+    ///
+    /// The armed position stays in `X_COMPARE` and still triggers if it is reached.
+    pub fn cancel_position_compare(&mut self) {
+        self.compare_schedule = None;
+    }
+
+    /// Advances the scheduled series once `XACTUAL` has passed the armed position.
+    ///
+    /// Has to be called often enough that the motor never passes two trigger positions between
+    /// two calls, or the second one is missed. Returns the number of triggers passed since the
+    /// last call, which is 0 or 1.
+    pub fn poll_position_compare(&mut self) -> Result<u32, Error<SPI::Error>> {
+        let schedule = match self.compare_schedule {
+            Some(schedule) => schedule,
+            None => return Ok(0),
+        };
+        let (_, xactual) = self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        let travelled = xactual.get().wrapping_sub(schedule.next);
+        if travelled.signum() != 0 && travelled.signum() != schedule.spacing.signum() {
+            return Ok(0);
+        }
+
+        let next = CompareSchedule {
+            next: schedule.next.wrapping_add(schedule.spacing),
+            spacing: schedule.spacing,
+            remaining: schedule.remaining - 1,
+        };
+        if next.remaining == 0 {
+            self.compare_schedule = None;
+        } else {
+            self.write_register(reg::X_COMPARE(next.next as u32))?;
+            self.compare_schedule = Some(next);
+        }
+        Ok(1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write};
+    use crate::reg::Address;
+
+    #[test]
+    fn series_advances_once_passed() {
+        let mut datagrams = vec![write(Address::X_COMPARE, (-100i32) as u32, 0)];
+        datagrams.extend(read_register(Address::XACTUAL, (-90i32) as u32));
+        datagrams.extend(read_register(Address::XACTUAL, (-100i32) as u32));
+        datagrams.push(write(Address::X_COMPARE, (-150i32) as u32, 0));
+        datagrams.extend(read_register(Address::XACTUAL, (-160i32) as u32));
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.schedule_position_compare(-100, -50, 2).unwrap();
+        assert_eq!(driver.poll_position_compare().unwrap(), 0);
+        assert_eq!(driver.poll_position_compare().unwrap(), 1);
+        assert_eq!(driver.position_compare_schedule(), Some(CompareSchedule { next: -150, spacing: -50, remaining: 1 }));
+        assert_eq!(driver.poll_position_compare().unwrap(), 1);
+        // The series is complete, nothing is read anymore.
+        assert_eq!(driver.position_compare_schedule(), None);
+        assert_eq!(driver.poll_position_compare().unwrap(), 0);
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_zero_spacing() {
+        let (mut driver, mut spi) = driver(&[]);
+        assert!(matches!(driver.schedule_position_compare(0, 0, 3), Err(Error::OutOfRange)));
+        assert_eq!(driver.position_compare_schedule(), None);
+        spi.done();
+    }
+}
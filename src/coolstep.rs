@@ -0,0 +1,318 @@
+//! CoolStep load adaptive current control.
+//!
+//! CoolStep lowers the motor current while StallGuard2 reports little load, and raises it again as
+//! the load grows. The window of `SG_RESULT` values it regulates to is set in `COOLCONF` in steps of
+//! 32: below `semin * 32` the current increases, at or above `(semin + semax + 1) * 32` it
+//! decreases. CoolStep is active only while the velocity lies between `TCOOLTHRS` and `THIGH`.
+//!
+//! [`CoolStepConfig`] expresses these settings as `SG_RESULT` thresholds and velocities in
+//! microsteps per second, and [`Tmc5130::tune_coolstep`] suggests thresholds from measurements
+//! of the unloaded motor.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::ramp::tstep_from_hz;
+use crate::{reg, Direction, Error, Tmc5130};
+
+/// Current increment for each `SG_RESULT` below the lower threshold, `COOLCONF::seup`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CurrentIncrement {
+    One = 0,
+    Two = 1,
+    Four = 2,
+    Eight = 3,
+}
+
+/// Number of `SG_RESULT` values above the upper threshold per current decrement,
+/// `COOLCONF::sedn`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CurrentDecrement {
+    Every32 = 0,
+    Every8 = 1,
+    Every2 = 2,
+    Every1 = 3,
+}
+
+/// The lowest current CoolStep reduces to, relative to `IRUN`, `COOLCONF::seimin`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MinimumCurrent {
+    Half,
+    Quarter,
+}
+
+/// Reasons CoolStep thresholds can not be expressed in `COOLCONF`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum CoolStepError {
+    /// The lower threshold does not round to a `semin` of 1 to 15.
+    InvalidLowerThreshold,
+    /// The upper threshold is not above the lower one, or more than 16 steps of 32 above it.
+    InvalidWindow,
+}
+
+/// CoolStep settings in physical units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CoolStepConfig {
+    /// `SG_RESULT` below which the current is increased, 32 to 480.
+    pub sg_lower: u16,
+    /// `SG_RESULT` from which the current is decreased, above `sg_lower`.
+    pub sg_upper: u16,
+    /// How fast the current increases.
+    pub increment: CurrentIncrement,
+    /// How fast the current decreases.
+    pub decrement: CurrentDecrement,
+    /// The lowest current CoolStep reduces to.
+    pub minimum: MinimumCurrent,
+    /// Velocity in microsteps per second above which CoolStep is active.
+    pub min_velocity_hz: f32,
+    /// Velocity in microsteps per second from which CoolStep is inactive again, written to `THIGH`.
+    /// `None` keeps the current `THIGH`, which also sets the high velocity and dcStep switch point.
+    pub max_velocity_hz: Option<f32>,
+}
+
+impl Default for CoolStepConfig {
+    fn default() -> Self {
+        Self {
+            sg_lower: 160,
+            sg_upper: 320,
+            increment: CurrentIncrement::Two,
+            decrement: CurrentDecrement::Every8,
+            minimum: MinimumCurrent::Half,
+            min_velocity_hz: 1_000.0,
+            max_velocity_hz: None,
+        }
+    }
+}
+
+impl CoolStepConfig {
+    /// Sets the CoolStep fields of `coolconf`, keeping the StallGuard2 fields.
+    ///
+    /// The thresholds are rounded to the nearest multiple of 32. Fails if they do not form a
+    /// window that `semin` and `semax` can express.
+    pub fn apply(&self, coolconf: reg::COOLCONF) -> Result<reg::COOLCONF, CoolStepError> {
+        let semin = (self.sg_lower + 16) / 32;
+        let upper = (self.sg_upper + 16) / 32;
+        if !(1..=15).contains(&semin) {
+            return Err(CoolStepError::InvalidLowerThreshold);
+        }
+        if upper <= semin || upper - semin - 1 > 15 {
+            return Err(CoolStepError::InvalidWindow);
+        }
+        let mut coolconf = coolconf;
+        coolconf.set_semin(semin);
+        coolconf.set_semax(upper - semin - 1);
+        coolconf.set_seup(self.increment as u16);
+        coolconf.set_sedn(self.decrement as u16);
+        coolconf.set_seimin(self.minimum == MinimumCurrent::Quarter);
+        Ok(coolconf)
+    }
+}
+
+/// Parameters of the CoolStep tuning run.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CoolStepTuning {
+    /// Velocity of the tuning run, written to `VMAX`.
+    pub velocity: u32,
+    /// Acceleration of the tuning run, written to `AMAX`.
+    pub acceleration: u16,
+    /// Direction of the tuning run.
+    pub direction: Direction,
+    /// Number of `DRV_STATUS` samples taken at velocity.
+    pub samples: u16,
+    /// Interval between two samples, in milliseconds.
+    pub sample_interval_ms: u32,
+    /// Share of the unloaded `SG_RESULT` kept as reserve against stalling, 0 to 1. The current
+    /// starts increasing once the load has used up the rest.
+    pub load_margin: f32,
+    /// Time allowed to reach the velocity, in milliseconds.
+    pub timeout_ms: u32,
+}
+
+impl Default for CoolStepTuning {
+    fn default() -> Self {
+        Self {
+            velocity: 100_000,
+            acceleration: 1_000,
+            direction: Direction::Positive,
+            samples: 64,
+            sample_interval_ms: 5,
+            load_margin: 0.5,
+            timeout_ms: 5_000,
+        }
+    }
+}
+
+/// Measurements of the CoolStep tuning run and the thresholds suggested from them.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CoolStepSuggestion {
+    /// The given configuration with the suggested `sg_lower` and `sg_upper`.
+    pub config: CoolStepConfig,
+    /// Mean unloaded `SG_RESULT`.
+    pub sg_mean: u16,
+    /// Lowest unloaded `SG_RESULT`.
+    pub sg_min: u16,
+    /// Highest unloaded `SG_RESULT`.
+    pub sg_max: u16,
+    /// Mean `CS_ACTUAL` during the run.
+    pub cs_mean: u8,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Writes a CoolStep configuration to `COOLCONF` and `TCOOLTHRS`.
+    ///
+    /// Fails with [`Error::CoolStep`] before anything is written if the thresholds can not be
+    /// expressed.
+    ///
+    /// `THIGH` is only written if `max_velocity_hz` is given. It is shared with
+    /// [`Tmc5130::set_dcstep`] and [`Tmc5130::set_velocity_thresholds`], so writing it moves the
+    /// switch point to the high velocity chopper settings and to dcStep as well. The velocities are
+    /// converted with the microstep resolution of the last known `CHOPCONF`.
+    pub fn set_coolstep(&mut self, config: &CoolStepConfig) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let coolconf = config.apply(*self.shadow.coolconf()).map_err(Error::CoolStep)?;
+        let mres = self.shadow.chopconf().mres();
+        let mut tcoolthrs = reg::TCOOLTHRS::default();
+        tcoolthrs.set(tstep_from_hz(config.min_velocity_hz, mres, self.clock_hz));
+        self.write_register(tcoolthrs)?;
+        if let Some(max_velocity_hz) = config.max_velocity_hz {
+            let mut thigh = reg::THIGH::default();
+            thigh.set(tstep_from_hz(max_velocity_hz, mres, self.clock_hz));
+            self.write_register(thigh)?;
+        }
+        self.write_register(coolconf)
+    }
+
+    /// Disables CoolStep by clearing `COOLCONF::semin`, so the motor runs at `IRUN`.
+    pub fn disable_coolstep(&mut self) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let mut coolconf = *self.shadow.coolconf();
+        coolconf.set_semin(0);
+        self.write_register(coolconf)
+    }
+
+    /// Runs the unloaded motor and suggests CoolStep thresholds for `base`.
+    ///
+    /// CoolStep is disabled and SpreadCycle used during the run, so `SG_RESULT` is measured at the
+    /// full run current. The suggested lower threshold keeps `load_margin` of the mean unloaded
+    /// `SG_RESULT` as reserve, and the upper threshold lies above it by twice the spread of the
+    /// measurements, so noise does not make the current oscillate. `GCONF`, `COOLCONF`,
+    /// `TCOOLTHRS`, `VMAX` and `AMAX` are restored and the motor stopped afterwards.
+    pub fn tune_coolstep<D>(&mut self, base: &CoolStepConfig, tuning: &CoolStepTuning, delay: &mut D) -> Result<CoolStepSuggestion, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let (_, gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        let coolconf = *self.shadow.coolconf();
+        let tcoolthrs = *self.shadow.tcoolthrs();
+        let vmax = *self.shadow.vmax();
+        let amax = *self.shadow.amax();
+
+        let result = self.tune_coolstep_inner(base, tuning, gconf, delay);
+        let stopped = self.stop(delay, tuning.sample_interval_ms * 1000, tuning.timeout_ms);
+
+        self.write_register(amax)?;
+        self.write_register(vmax)?;
+        self.write_register(tcoolthrs)?;
+        self.write_register(coolconf)?;
+        self.write_register(gconf)?;
+        stopped?;
+        result
+    }
+
+    fn tune_coolstep_inner<D>(&mut self, base: &CoolStepConfig, tuning: &CoolStepTuning, gconf: reg::GCONF, delay: &mut D) -> Result<CoolStepSuggestion, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let mut spread_cycle = gconf;
+        spread_cycle.set_en_pwm_mode(false);
+        self.write_register(spread_cycle)?;
+        self.disable_coolstep()?;
+        let mut tcoolthrs = reg::TCOOLTHRS::default();
+        tcoolthrs.set((1 << 20) - 1);
+        self.write_register(tcoolthrs)?;
+
+        let mut amax = reg::AMAX::default();
+        amax.set(tuning.acceleration);
+        self.write_register(amax)?;
+        let mut vmax = reg::VMAX::default();
+        vmax.set(tuning.velocity);
+        self.write_register(vmax)?;
+        self.write_register(tuning.direction.velocity_mode())?;
+        self.poll_ramp_stat(delay, tuning.sample_interval_ms * 1000, tuning.timeout_ms, |ramp_stat| ramp_stat.velocity_reached())?
+            .ok_or(Error::Timeout)?;
+
+        let mut sg_sum = 0u32;
+        let mut sg_min = u16::MAX;
+        let mut sg_max = 0u16;
+        let mut cs_sum = 0u32;
+        let samples = tuning.samples.max(1);
+        for _ in 0..samples {
+            delay.delay_ms(tuning.sample_interval_ms);
+            let (_, drv_status) = self.read_register::<reg::DRV_STATUS>().map_err(Error::Spi)?;
+            let sg = drv_status.sg_result() as u16;
+            sg_sum += sg as u32;
+            sg_min = sg_min.min(sg);
+            sg_max = sg_max.max(sg);
+            cs_sum += drv_status.cs_actual();
+        }
+        let sg_mean = (sg_sum / samples as u32) as u16;
+
+        let sg_lower = ((sg_mean as f32 * tuning.load_margin.clamp(0.0, 1.0)) as u16).max(32);
+        let sg_upper = (sg_lower + 2 * (sg_max - sg_min)).max(sg_lower + 32);
+        let config = CoolStepConfig { sg_lower, sg_upper, ..*base };
+        // Fail early if the measurements lead to thresholds the registers can not hold.
+        config.apply(reg::COOLCONF::default()).map_err(Error::CoolStep)?;
+        Ok(CoolStepSuggestion {
+            config,
+            sg_mean,
+            sg_min,
+            sg_max,
+            cs_mean: (cs_sum / samples as u32) as u8,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn window(sg_lower: u16, sg_upper: u16) -> Result<reg::COOLCONF, CoolStepError> {
+        CoolStepConfig { sg_lower, sg_upper, ..CoolStepConfig::default() }.apply(reg::COOLCONF::default())
+    }
+
+    #[test]
+    fn thresholds_round_to_steps_of_32() {
+        let coolconf = window(160, 320).unwrap();
+        assert_eq!((coolconf.semin(), coolconf.semax()), (5, 4));
+        let coolconf = window(170, 300).unwrap();
+        assert_eq!((coolconf.semin(), coolconf.semax()), (5, 3));
+        // The widest window.
+        let coolconf = window(32, 544).unwrap();
+        assert_eq!((coolconf.semin(), coolconf.semax()), (1, 15));
+    }
+
+    #[test]
+    fn keeps_stallguard_fields() {
+        let mut coolconf = reg::COOLCONF::default();
+        coolconf.set_sgt(-5);
+        coolconf.set_sfilt(true);
+        let config = CoolStepConfig { increment: CurrentIncrement::Eight, minimum: MinimumCurrent::Quarter, ..CoolStepConfig::default() };
+        let coolconf = config.apply(coolconf).unwrap();
+        assert_eq!((coolconf.sgt(), coolconf.sfilt()), (-5, true));
+        assert_eq!((coolconf.seup(), coolconf.seimin()), (3, true));
+    }
+
+    #[test]
+    fn rejects_windows_coolconf_can_not_hold() {
+        assert_eq!(window(0, 320), Err(CoolStepError::InvalidLowerThreshold));
+        assert_eq!(window(500, 600), Err(CoolStepError::InvalidLowerThreshold));
+        assert_eq!(window(160, 170), Err(CoolStepError::InvalidWindow));
+        assert_eq!(window(32, 576), Err(CoolStepError::InvalidWindow));
+    }
+}
@@ -0,0 +1,210 @@
+//! Motor current setup in amperes.
+//!
+//! The coil current is set by the current scale `CS` (`IHOLD_IRUN::irun` and `ihold`) relative to a
+//! full scale given by the sense resistor and the sense voltage range `CHOPCONF::vsense`:
+//!
+//! `I_RMS = (CS + 1) / 32 * V_FS / (R_SENSE + 20mΩ) / √2`
+//!
+//! where `V_FS` is 0.32V with `vsense` low and 0.18V with `vsense` high. With
+//! `GCONF::i_scale_analog` set, `V_FS` is further scaled by the voltage on AIN relative to 2.5V.
+//! `GCONF::internal_rsense` replaces the sense resistors by a reference current into AIN, which is
+//! not supported by these conversions.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Error, Tmc5130};
+
+/// Resistance of the sense path inside the chip, in ohms.
+const INTERNAL_RESISTANCE_OHMS: f32 = 0.02;
+/// The full scale sense voltage with `vsense` low.
+const VFS_LOW_SENSITIVITY: f32 = 0.32;
+/// The full scale sense voltage with `vsense` high.
+const VFS_HIGH_SENSITIVITY: f32 = 0.18;
+/// The AIN voltage at which `i_scale_analog` leaves the full scale unchanged.
+const AIN_REFERENCE_VOLTS: f32 = 2.5;
+
+/// The current sensing circuit of the board.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CurrentSense {
+    /// Value of the sense resistors, in ohms.
+    pub rsense_ohms: f32,
+    /// Voltage on AIN, only used with `GCONF::i_scale_analog` set.
+    pub ain_volts: f32,
+}
+
+impl CurrentSense {
+    /// A board with the given sense resistors and AIN at its 2.5V reference.
+    pub fn new(rsense_ohms: f32) -> Self {
+        Self { rsense_ohms, ain_volts: AIN_REFERENCE_VOLTS }
+    }
+
+    /// The RMS current per unit of `CS + 1`, in amperes.
+    fn amps_per_step(&self, vsense: bool, gconf: reg::GCONF) -> f32 {
+        let mut vfs = if vsense { VFS_HIGH_SENSITIVITY } else { VFS_LOW_SENSITIVITY };
+        if gconf.i_scale_analog() {
+            vfs *= self.ain_volts / AIN_REFERENCE_VOLTS;
+        }
+        vfs / (self.rsense_ohms + INTERNAL_RESISTANCE_OHMS) / 32.0 / core::f32::consts::SQRT_2
+    }
+
+    /// The RMS current of a current scale, in amperes.
+    pub fn current_rms(&self, cs: u8, vsense: bool, gconf: reg::GCONF) -> f32 {
+        (cs as f32 + 1.0) * self.amps_per_step(vsense, gconf)
+    }
+
+    /// The current scale closest to the given RMS current, clamped to 0..=31.
+    pub fn current_scale(&self, amps: f32, vsense: bool, gconf: reg::GCONF) -> u8 {
+        let cs = amps / self.amps_per_step(vsense, gconf) - 1.0 + 0.5;
+        if cs <= 0.0 { 0 } else if cs >= 31.0 { 31 } else { cs as u8 }
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Sets the run current `IRUN` in RMS amperes, returning the current actually set.
+    ///
+    /// `CHOPCONF::vsense` is chosen for the best resolution, which is the high sensitivity range
+    /// whenever the current fits in it. As `vsense` also scales the hold current, `IHOLD` is
+    /// rescaled to keep the hold current when the range changes.
+    pub fn set_run_current_rms(&mut self, amps: f32, sense: &CurrentSense) -> Result<f32, Error<SPI::Error>> {
+        let gconf = self.current_sense_gconf()?;
+        let (_, chopconf) = self.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+        let vsense = sense.current_rms(31, true, gconf) >= amps;
+
+        let mut ihold_irun = *self.shadow.ihold_irun();
+        let hold_amps = sense.current_rms(ihold_irun.ihold(), chopconf.vsense(), gconf);
+        ihold_irun.set_irun(sense.current_scale(amps, vsense, gconf));
+        ihold_irun.set_ihold(sense.current_scale(hold_amps, vsense, gconf));
+
+        let mut new_chopconf = chopconf;
+        new_chopconf.set_vsense(vsense);
+        // Lower the current before the higher full scale takes effect, and raise it only after the
+        // lower one has.
+        if vsense {
+            self.write_register(new_chopconf)?;
+            self.write_register(ihold_irun)?;
+        } else {
+            self.write_register(ihold_irun)?;
+            self.write_register(new_chopconf)?;
+        }
+        Ok(sense.current_rms(ihold_irun.irun(), vsense, gconf))
+    }
+
+    /// Sets the hold current `IHOLD` in RMS amperes, returning the current actually set.
+    ///
+    /// The hold current uses the range chosen for the run current, so it is limited to what that
+    /// range allows. Set the run current first.
+    pub fn set_hold_current_rms(&mut self, amps: f32, sense: &CurrentSense) -> Result<f32, Error<SPI::Error>> {
+        let gconf = self.current_sense_gconf()?;
+        let (_, chopconf) = self.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+        let mut ihold_irun = *self.shadow.ihold_irun();
+        ihold_irun.set_ihold(sense.current_scale(amps, chopconf.vsense(), gconf));
+        self.write_register(ihold_irun)?;
+        Ok(sense.current_rms(ihold_irun.ihold(), chopconf.vsense(), gconf))
+    }
+
+    /// The run current in RMS amperes, from the last known register values.
+    pub fn run_current_rms(&self, sense: &CurrentSense) -> f32 {
+        sense.current_rms(self.shadow.ihold_irun().irun(), self.shadow.chopconf().vsense(), *self.shadow.gconf())
+    }
+
+    /// The hold current in RMS amperes, from the last known register values.
+    pub fn hold_current_rms(&self, sense: &CurrentSense) -> f32 {
+        sense.current_rms(self.shadow.ihold_irun().ihold(), self.shadow.chopconf().vsense(), *self.shadow.gconf())
+    }
+
+    /// Reads `GCONF` and checks that the current is set by external sense resistors.
+    fn current_sense_gconf(&mut self) -> Result<reg::GCONF, Error<SPI::Error>> {
+        let (_, gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        if gconf.internal_rsense() {
+            return Err(Error::Unsupported);
+        }
+        Ok(gconf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write};
+    use crate::reg::Address;
+
+    #[test]
+    fn current_scale_round_trips_in_both_ranges() {
+        let sense = CurrentSense::new(0.11);
+        for vsense in [false, true] {
+            for cs in 0..=31 {
+                let amps = sense.current_rms(cs, vsense, reg::GCONF::default());
+                assert_eq!(sense.current_scale(amps, vsense, reg::GCONF::default()), cs);
+            }
+        }
+    }
+
+    #[test]
+    fn current_scale_clamps() {
+        let sense = CurrentSense::new(0.11);
+        assert_eq!(sense.current_scale(0.0, true, reg::GCONF::default()), 0);
+        assert_eq!(sense.current_scale(10.0, false, reg::GCONF::default()), 31);
+    }
+
+    #[test]
+    fn analog_scaling_follows_ain() {
+        let sense = CurrentSense { rsense_ohms: 0.11, ain_volts: 1.25 };
+        let mut gconf = reg::GCONF::default();
+        gconf.set_i_scale_analog(true);
+        let full = sense.current_rms(31, false, reg::GCONF::default());
+        assert!((sense.current_rms(31, false, gconf) - full / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn small_currents_use_high_sensitivity() {
+        let sense = CurrentSense::new(0.11);
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_toff(3);
+        let mut high = chopconf;
+        high.set_vsense(true);
+        // The hold current of 1/32 of the low range is kept in the high range.
+        let ihold = sense.current_scale(sense.current_rms(0, false, reg::GCONF::default()), true, reg::GCONF::default());
+        let irun = sense.current_scale(0.5, true, reg::GCONF::default());
+        let mut datagrams = read_register(Address::GCONF, 0).to_vec();
+        datagrams.extend(read_register(Address::CHOPCONF, chopconf.0));
+        // Raised full scale first, then the current scales.
+        datagrams.push(write(Address::CHOPCONF, high.0, 0));
+        datagrams.push(write(Address::IHOLD_IRUN, (irun as u32) << 8 | ihold as u32, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let amps = driver.set_run_current_rms(0.5, &sense).unwrap();
+        assert!((amps - 0.5).abs() < sense.current_rms(0, true, reg::GCONF::default()) / 2.0);
+        assert_eq!(irun, 15);
+        assert_eq!(ihold, 1);
+        spi.done();
+    }
+
+    #[test]
+    fn large_currents_use_low_sensitivity() {
+        let sense = CurrentSense::new(0.11);
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_vsense(true);
+        let irun = sense.current_scale(1.2, false, reg::GCONF::default());
+        let mut datagrams = read_register(Address::GCONF, 0).to_vec();
+        datagrams.extend(read_register(Address::CHOPCONF, chopconf.0));
+        // Current scales first, then the lowered full scale.
+        datagrams.push(write(Address::IHOLD_IRUN, (irun as u32) << 8, 0));
+        datagrams.push(write(Address::CHOPCONF, 0, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_run_current_rms(1.2, &sense).unwrap();
+        assert_eq!(irun, 21);
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_internal_rsense() {
+        let mut gconf = reg::GCONF::default();
+        gconf.set_internal_rsense(true);
+        let (mut driver, mut spi) = driver(&read_register(Address::GCONF, gconf.0));
+        assert!(matches!(driver.set_run_current_rms(0.5, &CurrentSense::new(0.11)), Err(Error::Unsupported)));
+        spi.done();
+    }
+}
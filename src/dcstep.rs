@@ -0,0 +1,193 @@
+//! dcStep load dependent speed control.
+//!
+//! Above `VDCMIN`, dcStep drives the motor in fullsteps and lets it slow down under load instead
+//! of losing steps. It relies on the high velocity chopper settings, so `CHOPCONF::vhighfs` and
+//! `vhighchm` are set and `THIGH` is placed at `VDCMIN`, which makes the driver switch to fullstep
+//! and constant off time chopping where dcStep takes over.
+//!
+//! `DCCTRL::dc_time` limits the PWM on time used for commutation and has to be slightly above the
+//! effective blank time `TBL`. `DCCTRL::dc_sg` enables step loss detection, and is set slightly
+//! above `dc_time / 16`.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::chopper::ChopperConfig;
+use crate::ramp::{tstep_from_hz, velocity_from_hz};
+use crate::{reg, Error, Tmc5130};
+
+/// Lowest `VDCMIN` that enables dcStep, as only bits 22..8 are used.
+const VDCMIN_MIN: u32 = 1 << 8;
+/// Highest `VDCMIN`.
+const VDCMIN_MAX: u32 = (1 << 23) - 1;
+
+/// dcStep settings in physical units.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DcStepConfig {
+    /// Velocity in microsteps per second above which dcStep is active.
+    pub min_velocity_hz: f32,
+    /// Clock cycles `dc_time` is set above the blank time.
+    pub dc_time_margin: u16,
+    /// Enables step loss detection through `dc_sg`.
+    pub stall_detection: bool,
+}
+
+impl Default for DcStepConfig {
+    fn default() -> Self {
+        Self {
+            min_velocity_hz: 20_000.0,
+            dc_time_margin: 4,
+            stall_detection: true,
+        }
+    }
+}
+
+/// dcStep state read from `DRV_STATUS` and `LOST_STEPS`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DcStepStatus {
+    /// The driver runs in fullstep mode, as it does while dcStep is active.
+    pub fullstep: bool,
+    /// dcStep detected a stall, `DRV_STATUS::stallguard`.
+    pub stalled: bool,
+    /// Steps dcStep could not execute, only counted in step/dir mode.
+    pub lost_steps: u32,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Enables dcStep with settings derived from the current chopper configuration.
+    ///
+    /// Fails with [`Error::Unsupported`] if `CHOPCONF` does not hold a valid, enabled chopper
+    /// configuration to derive `dc_time` from, and with [`Error::OutOfRange`] if `min_velocity_hz`
+    /// is outside of what `VDCMIN` can hold. Nothing is written in either case. The velocities are
+    /// converted with the microstep resolution of `CHOPCONF`.
+    pub fn set_dcstep(&mut self, config: &DcStepConfig) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let (_, mut chopconf) = self.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+        let chopper = ChopperConfig::from_chopconf(chopconf).map_err(|_| Error::Unsupported)?;
+
+        let dc_time = chopper.blank_time_clocks() + config.dc_time_margin as u32;
+        if dc_time > 0x3FF {
+            return Err(Error::OutOfRange);
+        }
+        let mut dcctrl = reg::DCCTRL::default();
+        dcctrl.set_dc_time(dc_time as u16);
+        if config.stall_detection {
+            dcctrl.set_dc_sg((dc_time / 16 + 1) as u16);
+        }
+
+        // The chip only compares bits 22..8 of `VDCMIN`, a smaller value disables dcStep.
+        let vdcmin_value = velocity_from_hz(config.min_velocity_hz, self.clock_hz);
+        if !(VDCMIN_MIN..=VDCMIN_MAX).contains(&vdcmin_value) {
+            return Err(Error::OutOfRange);
+        }
+        let mut vdcmin = reg::VDCMIN::default();
+        vdcmin.set(vdcmin_value);
+        let mut thigh = reg::THIGH::default();
+        thigh.set(tstep_from_hz(config.min_velocity_hz, chopconf.mres(), self.clock_hz));
+
+        chopconf.set_vhighfs(true);
+        chopconf.set_vhighchm(true);
+        self.write_register(chopconf)?;
+        self.write_register(thigh)?;
+        self.write_register(dcctrl)?;
+        self.write_register(vdcmin)
+    }
+
+    /// Disables dcStep by clearing `VDCMIN` and `DCCTRL`.
+    ///
+    /// The high velocity chopper settings and `THIGH` are left as they are.
+    pub fn disable_dcstep(&mut self) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        self.write_register(reg::DCCTRL::default())?;
+        self.write_register(reg::VDCMIN::default())
+    }
+
+    /// Reads whether dcStep is running and whether it detects stalls.
+    pub fn dcstep_status(&mut self) -> Result<DcStepStatus, Error<SPI::Error>> {
+        let (_, drv_status) = self.read_register::<reg::DRV_STATUS>().map_err(Error::Spi)?;
+        let (_, lost_steps) = self.read_register::<reg::LOST_STEPS>().map_err(Error::Spi)?;
+        Ok(DcStepStatus {
+            fullstep: drv_status.fsactive(),
+            stalled: drv_status.stallguard(),
+            lost_steps: lost_steps.get(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write};
+    use crate::reg::Address;
+
+    /// A clock at which a ramp generator velocity equals microsteps per second.
+    const CLOCK_HZ: u32 = 1 << 24;
+
+    /// SpreadCycle with `TOFF` 3, a blank time of 36 clocks and 16 microsteps.
+    fn chopconf() -> reg::CHOPCONF {
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_toff(3);
+        chopconf.set_tbl(2);
+        chopconf.set_mres(4);
+        chopconf
+    }
+
+    /// `chopconf` with the high velocity settings dcStep relies on.
+    fn high_velocity() -> reg::CHOPCONF {
+        let mut chopconf = chopconf();
+        chopconf.set_vhighfs(true);
+        chopconf.set_vhighchm(true);
+        chopconf
+    }
+
+    #[test]
+    fn derives_settings_from_the_chopper() {
+        let mut datagrams = read_register(Address::CHOPCONF, chopconf().0).to_vec();
+        datagrams.extend([
+            write(Address::CHOPCONF, high_velocity().0, 0),
+            // 2^24 / (20000 * 16).
+            write(Address::THIGH, 52, 0),
+            // `dc_time` 36 + 4 and `dc_sg` 40 / 16 + 1.
+            write(Address::DCCTRL, 3 << 16 | 40, 0),
+            write(Address::VDCMIN, 20_000, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_clock_frequency(CLOCK_HZ);
+        driver.set_dcstep(&DcStepConfig::default()).unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn stall_detection_is_optional() {
+        let mut datagrams = read_register(Address::CHOPCONF, chopconf().0).to_vec();
+        datagrams.extend([
+            write(Address::CHOPCONF, high_velocity().0, 0),
+            write(Address::THIGH, 4_096, 0),
+            write(Address::DCCTRL, 36, 0),
+            write(Address::VDCMIN, 256, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_clock_frequency(CLOCK_HZ);
+        let config = DcStepConfig { min_velocity_hz: 256.0, dc_time_margin: 0, stall_detection: false };
+        driver.set_dcstep(&config).unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_velocities_vdcmin_ignores() {
+        let (mut driver, mut spi) = driver(&read_register(Address::CHOPCONF, chopconf().0));
+        driver.set_clock_frequency(CLOCK_HZ);
+        let config = DcStepConfig { min_velocity_hz: 255.0, ..Default::default() };
+        assert!(matches!(driver.set_dcstep(&config), Err(Error::OutOfRange)));
+        spi.done();
+    }
+
+    #[test]
+    fn requires_an_enabled_chopper() {
+        let (mut driver, mut spi) = driver(&read_register(Address::CHOPCONF, 0));
+        assert!(matches!(driver.set_dcstep(&DcStepConfig::default()), Err(Error::Unsupported)));
+        spi.done();
+    }
+}
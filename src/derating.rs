@@ -0,0 +1,240 @@
+//! Current derating on the overtemperature pre-warning.
+//!
+//! The driver sets `DRV_STATUS::otpw` well before it reaches the `ot` shutdown temperature.
+//! [`Derating`] reacts to the pre-warning by lowering `IHOLD_IRUN::irun`, and optionally `VMAX`,
+//! one step at a time for as long as it persists. Once it has cleared for a while, the steps are
+//! undone one by one, so the temperature is not driven straight back up to the warning threshold.
+//!
+//! The policy runs from [`Derating::poll`], to be called periodically, for instance from the main
+//! loop. The polls are the time base of all its delays.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Error, Tmc5130};
+
+/// Parameters of the derating policy.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeratingConfig {
+    /// Reduction of the current scale `IRUN` per step.
+    pub current_step: u8,
+    /// Lowest current scale the derating reduces `IRUN` to.
+    pub min_current: u8,
+    /// Reduction of `VMAX` per step, in percent of the undisturbed `VMAX`. 0 leaves `VMAX` alone.
+    pub velocity_step_percent: u8,
+    /// Lowest `VMAX` the derating reduces to, in percent of the undisturbed `VMAX`.
+    pub min_velocity_percent: u8,
+    /// Polls between two derating steps while the pre-warning persists.
+    pub step_polls: u16,
+    /// Polls the pre-warning has to stay cleared before a step is undone.
+    pub recovery_polls: u16,
+}
+
+impl Default for DeratingConfig {
+    fn default() -> Self {
+        Self {
+            current_step: 2,
+            min_current: 8,
+            velocity_step_percent: 10,
+            min_velocity_percent: 50,
+            step_polls: 10,
+            recovery_polls: 50,
+        }
+    }
+}
+
+/// The state of the derating after a poll.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DeratingState {
+    /// The pre-warning was set in the last poll.
+    pub warning: bool,
+    /// Number of derating steps in effect, 0 if the driver runs undisturbed.
+    pub level: u8,
+    /// The current scale `IRUN` in effect.
+    pub irun: u8,
+    /// `VMAX` in effect.
+    pub vmax: u32,
+}
+
+/// The derating policy and its state.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Derating {
+    config: DeratingConfig,
+    level: u8,
+    base_irun: u8,
+    base_vmax: u32,
+    polls: u16,
+    warning: bool,
+}
+
+impl Derating {
+    /// A policy that has not derated yet.
+    pub fn new(config: DeratingConfig) -> Self {
+        Self { config, level: 0, base_irun: 0, base_vmax: 0, polls: 0, warning: false }
+    }
+
+    /// The configuration of the policy.
+    pub fn config(&self) -> DeratingConfig {
+        self.config
+    }
+
+    /// Number of derating steps in effect.
+    pub fn level(&self) -> u8 {
+        self.level
+    }
+
+    /// Whether the driver runs with reduced settings.
+    pub fn is_derated(&self) -> bool {
+        self.level > 0
+    }
+
+    /// Reads `DRV_STATUS::otpw` and takes or undoes a derating step if due.
+    ///
+    /// `IRUN` and `VMAX` are taken from the shadow when derating starts and written back when the
+    /// last step is undone, so they should not be changed while derated.
+    pub fn poll<SPI>(&mut self, driver: &mut Tmc5130<SPI>) -> Result<DeratingState, Error<SPI::Error>>
+    where SPI: SpiDevice<u8>
+    {
+        let (_, drv_status) = driver.read_register::<reg::DRV_STATUS>().map_err(Error::Spi)?;
+        let warning = drv_status.otpw();
+        if warning != self.warning {
+            self.warning = warning;
+            self.polls = 0;
+        }
+        self.polls = self.polls.saturating_add(1);
+
+        let level = if warning {
+            if self.level == 0 {
+                self.base_irun = driver.shadow().ihold_irun().irun();
+                self.base_vmax = driver.shadow().vmax().get();
+            }
+            // Step down at once on a new warning, then every `step_polls`.
+            let due = self.polls == 1 || self.polls > self.config.step_polls.max(1);
+            if due && !self.at_limit() { Some(self.level + 1) } else { None }
+        } else {
+            let due = self.level > 0 && self.polls > self.config.recovery_polls;
+            if due { Some(self.level - 1) } else { None }
+        };
+        if let Some(level) = level {
+            self.level = level;
+            self.polls = 1;
+            self.apply(driver)?;
+        }
+        Ok(self.state(driver))
+    }
+
+    /// The state of the derating, with the values of the last known registers.
+    pub fn state<SPI>(&self, driver: &Tmc5130<SPI>) -> DeratingState
+    where SPI: SpiDevice<u8>
+    {
+        DeratingState {
+            warning: self.warning,
+            level: self.level,
+            irun: driver.shadow().ihold_irun().irun(),
+            vmax: driver.shadow().vmax().get(),
+        }
+    }
+
+    /// Whether another step would change neither the current nor the velocity.
+    fn at_limit(&self) -> bool {
+        let level = self.level as u32 + 1;
+        self.irun(self.level as u32) == self.irun(level) && self.vmax(self.level as u32) == self.vmax(level)
+    }
+
+    fn irun(&self, level: u32) -> u8 {
+        let reduction = level * self.config.current_step as u32;
+        let min = self.config.min_current.min(self.base_irun) as u32;
+        (self.base_irun as u32).saturating_sub(reduction).max(min) as u8
+    }
+
+    fn vmax(&self, level: u32) -> u32 {
+        let percent = (100 - (level * self.config.velocity_step_percent as u32).min(100))
+            .max(self.config.min_velocity_percent.min(100) as u32);
+        (self.base_vmax as u64 * percent as u64 / 100) as u32
+    }
+
+    fn apply<SPI>(&self, driver: &mut Tmc5130<SPI>) -> Result<reg::SPISTATUS, Error<SPI::Error>>
+    where SPI: SpiDevice<u8>
+    {
+        let mut ihold_irun = *driver.shadow().ihold_irun();
+        ihold_irun.set_irun(self.irun(self.level as u32));
+        let status = driver.write_register(ihold_irun)?;
+        if self.config.velocity_step_percent == 0 {
+            return Ok(status);
+        }
+        let mut vmax = reg::VMAX::default();
+        vmax.set(self.vmax(self.level as u32));
+        driver.write_register(vmax)
+    }
+}
+
+impl Default for Derating {
+    fn default() -> Self {
+        Self::new(DeratingConfig::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write, Datagram};
+    use crate::reg::Address;
+
+    const OTPW: u32 = 1 << 26;
+
+    fn polls(otpw: bool, count: usize) -> Vec<Datagram> {
+        (0..count).flat_map(|_| read_register(Address::DRV_STATUS, if otpw { OTPW } else { 0 })).collect()
+    }
+
+    /// `IHOLD_IRUN` with the given `IRUN` and the default `IHOLD` of 0.
+    fn irun(irun: u32) -> Datagram {
+        write(Address::IHOLD_IRUN, irun << 8, 0)
+    }
+
+    #[test]
+    fn derates_to_floor_and_recovers_after_hold_off() {
+        let mut datagrams = polls(true, 1);
+        datagrams.push(irun(23));
+        datagrams.extend(polls(true, 1));
+        datagrams.push(irun(20));
+        // At the floor, no further step.
+        datagrams.extend(polls(true, 1));
+        // The warning clears, each step is undone after the hold-off.
+        datagrams.extend(polls(false, 3));
+        datagrams.push(irun(23));
+        datagrams.extend(polls(false, 2));
+        datagrams.push(irun(31));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let config = DeratingConfig { current_step: 8, min_current: 20, velocity_step_percent: 0, step_polls: 1, recovery_polls: 2, ..DeratingConfig::default() };
+        let mut derating = Derating::new(config);
+
+        assert_eq!(derating.poll(&mut driver).unwrap(), DeratingState { warning: true, level: 1, irun: 23, vmax: 0 });
+        assert_eq!(derating.poll(&mut driver).unwrap().irun, 20);
+        assert_eq!(derating.poll(&mut driver).unwrap().level, 2);
+        for _ in 0..2 {
+            assert_eq!(derating.poll(&mut driver).unwrap().level, 2);
+        }
+        assert_eq!(derating.poll(&mut driver).unwrap().level, 1);
+        assert_eq!(derating.poll(&mut driver).unwrap().level, 1);
+        let state = derating.poll(&mut driver).unwrap();
+        assert_eq!((state.warning, state.level, state.irun), (false, 0, 31));
+        assert!(!derating.is_derated());
+        spi.done();
+    }
+
+    #[test]
+    fn reduces_velocity_with_current() {
+        let mut datagrams = polls(true, 1);
+        datagrams.extend([irun(29), write(Address::VMAX, 750, 0)]);
+        datagrams.extend(polls(true, 1));
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.shadow.set_state(reg::State::from_addr_and_data(Address::VMAX, 1_000));
+        let mut derating = Derating::new(DeratingConfig { velocity_step_percent: 25, ..DeratingConfig::default() });
+        assert_eq!(derating.poll(&mut driver).unwrap().vmax, 750);
+        // The next step is only due after `step_polls`.
+        assert_eq!(derating.poll(&mut driver).unwrap().level, 1);
+        spi.done();
+    }
+}
@@ -0,0 +1,189 @@
+//! Direct coil current control through `GCONF::direct_mode`.
+//!
+//! In direct mode the microstep sequencer is bypassed and `XTARGET` holds the signed currents of
+//! coil A (bits 8..0) and coil B (bits 24..16) instead of a target position. The currents are scaled
+//! by `IHOLD`. As `XTARGET` no longer means a position, writes to `XTARGET`, `XACTUAL` and
+//! `RAMPMODE` are refused while direct mode is active, which keeps the positioning procedures of the
+//! driver from running.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address};
+use crate::{Error, Tmc5130};
+
+/// The highest magnitude of a coil current in direct mode.
+pub const COIL_CURRENT_MAX: i16 = 255;
+
+/// Whether a write to `addr` is allowed with direct mode in the given state.
+pub(crate) fn ramp_write_allowed(direct_mode: bool, addr: Address) -> bool {
+    !(direct_mode && matches!(addr, Address::XTARGET | Address::XACTUAL | Address::RAMPMODE))
+}
+
+/// Packs the signed 9-bit coil currents into the `XTARGET` layout used in direct mode.
+fn coil_currents_to_xtarget(coil_a: i16, coil_b: i16) -> u32 {
+    (coil_a as u32 & 0x1FF) | ((coil_b as u32 & 0x1FF) << 16)
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Whether direct mode is enabled, according to the last known `GCONF`.
+    pub fn direct_mode(&self) -> bool {
+        self.shadow.gconf().direct_mode()
+    }
+
+    /// Switches to direct coil current control.
+    ///
+    /// The motor has to be at standstill. The ramp generator is put on hold and the coils start
+    /// with the currents of `MSCURACT`, so the rotor does not move when the sequencer is bypassed.
+    pub fn enter_direct_mode(&mut self) -> Result<(), Error<SPI::Error>> {
+        if self.direct_mode() {
+            return Ok(());
+        }
+        let (_, ramp_stat) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+        if !ramp_stat.vzero() {
+            return Err(Error::NotAtStandstill);
+        }
+        self.write_register(reg::RAMPMODE::HOLD)?;
+        let (_, mscuract) = self.read_register::<reg::MSCURACT>().map_err(Error::Spi)?;
+        self.write_raw(Address::XTARGET, mscuract.0 & 0x01FF_01FF).map_err(Error::Spi)?;
+        let (_, mut gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        gconf.set_direct_mode(true);
+        self.write_register(gconf)?;
+        Ok(())
+    }
+
+    /// Returns to the microstep sequencer and the ramp generator.
+    ///
+    /// `XTARGET` is set back to `XACTUAL`, which did not change in direct mode, and the ramp
+    /// generator is left in positioning mode at standstill. The coil currents return to the
+    /// microstep table position given by `MSCNT`.
+    pub fn leave_direct_mode(&mut self) -> Result<(), Error<SPI::Error>> {
+        if !self.direct_mode() {
+            return Ok(());
+        }
+        let (_, mut gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        gconf.set_direct_mode(false);
+        self.write_register(gconf)?;
+        self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        let xactual = self.shadow.xactual().get();
+        self.write_raw(Address::XTARGET, xactual as u32).map_err(Error::Spi)?;
+        self.write_register(reg::RAMPMODE::POSITIONING)?;
+        Ok(())
+    }
+
+    /// Sets the signed currents of coil A and B, each from -255 to 255.
+    ///
+    /// Fails with [`Error::OutOfRange`] if a current exceeds that range, and with
+    /// [`Error::DirectModeInactive`] outside of direct mode.
+    pub fn set_coil_currents(&mut self, coil_a: i16, coil_b: i16) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        if !self.direct_mode() {
+            return Err(Error::DirectModeInactive);
+        }
+        let range = -COIL_CURRENT_MAX..=COIL_CURRENT_MAX;
+        if !range.contains(&coil_a) || !range.contains(&coil_b) {
+            return Err(Error::OutOfRange);
+        }
+        let (status, _) = self.write_raw(Address::XTARGET, coil_currents_to_xtarget(coil_a, coil_b)).map_err(Error::Spi)?;
+        Ok(status)
+    }
+
+    /// Plays a waveform of coil A and B currents, one sample every `interval_us` microseconds.
+    ///
+    /// Stops at the first sample out of range, the samples before it have been output.
+    pub fn stream_coil_currents<D, I>(&mut self, samples: I, interval_us: u32, delay: &mut D) -> Result<(), Error<SPI::Error>>
+    where D: DelayNs,
+          I: IntoIterator<Item = (i16, i16)>
+    {
+        for (coil_a, coil_b) in samples {
+            self.set_coil_currents(coil_a, coil_b)?;
+            delay.delay_us(interval_us);
+        }
+        Ok(())
+    }
+
+    /// Refuses writes that command the ramp generator while direct mode is active.
+    pub(crate) fn direct_mode_write_guard(&self, addr: Address) -> Result<(), Error<SPI::Error>> {
+        if ramp_write_allowed(self.direct_mode(), addr) {
+            Ok(())
+        } else {
+            Err(Error::DirectModeActive)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backlash::Backlash;
+    use crate::mock_peripherals::{driver, read_register, write, VZERO};
+
+    const DIRECT_MODE: u32 = 1 << 16;
+    const GCONF: u32 = 1 << 2;
+
+    #[test]
+    fn entering_seeds_the_coils_with_mscuract() {
+        let mut datagrams = read_register(Address::RAMP_STAT, VZERO).to_vec();
+        datagrams.push(write(Address::RAMPMODE, 3, 0));
+        datagrams.extend(read_register(Address::MSCURACT, 0xFFF6_FEB5));
+        datagrams.push(write(Address::XTARGET, 0x01F6_00B5, 0));
+        datagrams.extend(read_register(Address::GCONF, GCONF));
+        datagrams.push(write(Address::GCONF, GCONF | DIRECT_MODE, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.enter_direct_mode().unwrap();
+        assert!(driver.direct_mode());
+        // Already in direct mode.
+        driver.enter_direct_mode().unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn entering_requires_standstill() {
+        let (mut driver, mut spi) = driver(&read_register(Address::RAMP_STAT, 0));
+        assert!(matches!(driver.enter_direct_mode(), Err(Error::NotAtStandstill)));
+        assert!(!driver.direct_mode());
+        spi.done();
+    }
+
+    #[test]
+    fn guards_ramp_generator_writes() {
+        let (mut driver, mut spi) = driver(&[write(Address::XTARGET, 0x00FF_01FF, 0), write(Address::VMAX, 1_000, 0)]);
+        driver.shadow.set_state(reg::State::from_addr_and_data(Address::GCONF, DIRECT_MODE));
+        assert!(matches!(driver.write_register(reg::RAMPMODE::POSITIONING), Err(Error::DirectModeActive)));
+        assert!(matches!(driver.write_register(reg::XTARGET(0)), Err(Error::DirectModeActive)));
+        assert!(matches!(driver.write_register(reg::XACTUAL(0)), Err(Error::DirectModeActive)));
+        assert!(matches!(driver.set_coil_currents(256, 0), Err(Error::OutOfRange)));
+        assert!(matches!(driver.set_coil_currents(0, -256), Err(Error::OutOfRange)));
+        driver.set_coil_currents(-1, 255).unwrap();
+        let mut vmax = reg::VMAX::default();
+        vmax.set(1_000);
+        driver.write_register(vmax).unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn coil_currents_require_direct_mode() {
+        let (mut driver, mut spi) = driver(&[]);
+        assert!(matches!(driver.set_coil_currents(0, 0), Err(Error::DirectModeInactive)));
+        spi.done();
+    }
+
+    #[test]
+    fn leaving_holds_position_in_chip_coordinates() {
+        let mut datagrams = read_register(Address::GCONF, GCONF | DIRECT_MODE).to_vec();
+        datagrams.push(write(Address::GCONF, GCONF, 0));
+        datagrams.extend(read_register(Address::XACTUAL, 1_234));
+        datagrams.push(write(Address::XTARGET, 1_234, 0));
+        datagrams.push(write(Address::RAMPMODE, 0, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.shadow.set_state(reg::State::from_addr_and_data(Address::GCONF, GCONF | DIRECT_MODE));
+        driver.backlash = Some(Backlash { distance: 10, offset: -10, direction: Some(crate::Direction::Negative) });
+        driver.leave_direct_mode().unwrap();
+        assert!(!driver.direct_mode());
+        // Already left.
+        driver.leave_direct_mode().unwrap();
+        spi.done();
+    }
+}
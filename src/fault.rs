@@ -0,0 +1,379 @@
+//! Typed driver fault monitoring from `DRV_STATUS` and `GSTAT`.
+//!
+//! [`FaultMonitor`] turns the fault bits of the chip into [`Fault`]s. A condition has to be seen in
+//! a number of consecutive polls before it is reported, which filters out single glitches, for
+//! instance of the open load detection. Reported faults stay latched until they are acknowledged,
+//! even if the condition goes away in the meantime, so no fault is missed between two looks at
+//! the monitor.
+//!
+//! The monitor either polls the chip on every call of [`FaultMonitor::poll`], or reacts to the
+//! `driver_error` and `reset_flag` bits of the status returned with every datagram in
+//! [`FaultMonitor::on_status`]. `driver_error` covers shutdowns only, the overtemperature
+//! pre-warning and open load are found by polling.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Error, Tmc5130};
+
+/// A driver fault.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Fault {
+    /// The overtemperature pre-warning threshold is exceeded, `DRV_STATUS::otpw`.
+    OvertemperaturePreWarning,
+    /// The driver shut down due to overtemperature, `DRV_STATUS::ot`.
+    OvertemperatureShutdown,
+    /// The driver shut down due to a short to ground on phase A, `DRV_STATUS::s2ga`.
+    ShortPhaseA,
+    /// The driver shut down due to a short to ground on phase B, `DRV_STATUS::s2gb`.
+    ShortPhaseB,
+    /// Open load detected on phase A, `DRV_STATUS::ola`.
+    OpenLoadPhaseA,
+    /// Open load detected on phase B, `DRV_STATUS::olb`.
+    OpenLoadPhaseB,
+    /// The charge pump undervoltage disabled the driver, `GSTAT::uv_cp`.
+    Undervoltage,
+    /// The chip was reset and lost its configuration, `GSTAT::reset`.
+    Reset,
+    /// StallGuard2 or dcStep detected a stall, `DRV_STATUS::stallguard`.
+    Stall,
+}
+
+impl Fault {
+    /// All faults, in the order of their bits in [`Faults`].
+    pub const ALL: [Fault; 9] = [
+        Fault::OvertemperaturePreWarning,
+        Fault::OvertemperatureShutdown,
+        Fault::ShortPhaseA,
+        Fault::ShortPhaseB,
+        Fault::OpenLoadPhaseA,
+        Fault::OpenLoadPhaseB,
+        Fault::Undervoltage,
+        Fault::Reset,
+        Fault::Stall,
+    ];
+
+    /// Whether the fault disables the driver.
+    pub fn is_shutdown(self) -> bool {
+        matches!(self, Fault::OvertemperatureShutdown | Fault::ShortPhaseA | Fault::ShortPhaseB | Fault::Undervoltage)
+    }
+
+    fn bit(self) -> u16 {
+        1 << self as u16
+    }
+}
+
+/// A set of faults.
+#[derive(Clone, Copy, Debug, Default, Eq, Hash, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Faults(u16);
+
+impl Faults {
+    /// The empty set.
+    pub const NONE: Self = Faults(0);
+
+    /// The faults present in `DRV_STATUS` and `GSTAT`.
+    pub fn from_registers(drv_status: reg::DRV_STATUS, gstat: reg::GSTAT) -> Self {
+        let mut faults = Faults::NONE;
+        let present = [
+            drv_status.otpw(),
+            drv_status.ot(),
+            drv_status.s2ga(),
+            drv_status.s2gb(),
+            drv_status.ola(),
+            drv_status.olb(),
+            gstat.uv_cp(),
+            gstat.reset(),
+            drv_status.stallguard(),
+        ];
+        for (fault, present) in Fault::ALL.into_iter().zip(present) {
+            if present {
+                faults.insert(fault);
+            }
+        }
+        faults
+    }
+
+    /// Whether the set contains `fault`.
+    pub fn contains(self, fault: Fault) -> bool {
+        self.0 & fault.bit() != 0
+    }
+
+    /// Adds `fault` to the set.
+    pub fn insert(&mut self, fault: Fault) {
+        self.0 |= fault.bit();
+    }
+
+    /// Removes `fault` from the set.
+    pub fn remove(&mut self, fault: Fault) {
+        self.0 &= !fault.bit();
+    }
+
+    /// Whether the set is empty.
+    pub fn is_empty(self) -> bool {
+        self.0 == 0
+    }
+
+    /// The faults of the set.
+    pub fn iter(self) -> impl Iterator<Item = Fault> {
+        Fault::ALL.into_iter().filter(move |&fault| self.contains(fault))
+    }
+}
+
+impl From<Fault> for Faults {
+    fn from(fault: Fault) -> Self {
+        Faults(fault.bit())
+    }
+}
+
+impl core::ops::BitOr for Faults {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self {
+        Faults(self.0 | other.0)
+    }
+}
+
+/// Automatic actions taken when a fault is reported.
+pub trait FaultPolicy<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Called once for each newly latched fault.
+    fn on_fault(&mut self, driver: &mut Tmc5130<SPI>, fault: Fault) -> Result<(), Error<SPI::Error>>;
+}
+
+/// Takes no action.
+impl<SPI> FaultPolicy<SPI> for ()
+where
+    SPI: SpiDevice<u8>,
+{
+    fn on_fault(&mut self, _driver: &mut Tmc5130<SPI>, _fault: Fault) -> Result<(), Error<SPI::Error>> {
+        Ok(())
+    }
+}
+
+/// Disables the driver by clearing `CHOPCONF::toff` on faults that shut it down, so it stays off
+/// after the fault flags are cleared. `CHOPCONF` is read from the chip first, so only `toff`
+/// changes. Restore the chopper configuration to enable it again.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct DisableOnShutdown;
+
+impl<SPI> FaultPolicy<SPI> for DisableOnShutdown
+where
+    SPI: SpiDevice<u8>,
+{
+    fn on_fault(&mut self, driver: &mut Tmc5130<SPI>, fault: Fault) -> Result<(), Error<SPI::Error>> {
+        if fault.is_shutdown() {
+            let (_, mut chopconf) = driver.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+            chopconf.set_toff(0);
+            driver.write_register(chopconf)?;
+        }
+        Ok(())
+    }
+}
+
+/// Debounces and latches driver faults.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct FaultMonitor {
+    debounce: u8,
+    counters: [u8; Fault::ALL.len()],
+    active: Faults,
+    latched: Faults,
+}
+
+impl FaultMonitor {
+    /// A monitor reporting conditions seen in `debounce` consecutive polls, at least one.
+    pub fn new(debounce: u8) -> Self {
+        Self {
+            debounce: debounce.max(1),
+            counters: [0; Fault::ALL.len()],
+            active: Faults::NONE,
+            latched: Faults::NONE,
+        }
+    }
+
+    /// Reads `DRV_STATUS` and `GSTAT` and returns the newly latched faults.
+    ///
+    /// The policy is called for each of them before returning.
+    pub fn poll<SPI, P>(&mut self, driver: &mut Tmc5130<SPI>, policy: &mut P) -> Result<Faults, Error<SPI::Error>>
+    where
+        SPI: SpiDevice<u8>,
+        P: FaultPolicy<SPI>,
+    {
+        let (_, drv_status) = driver.read_register::<reg::DRV_STATUS>().map_err(Error::Spi)?;
+        let (_, gstat) = driver.read_register::<reg::GSTAT>().map_err(Error::Spi)?;
+        let raised = self.update(Faults::from_registers(drv_status, gstat));
+        for fault in raised.iter() {
+            policy.on_fault(driver, fault)?;
+        }
+        Ok(raised)
+    }
+
+    /// Polls only if the status of a datagram flags a driver error or reset, or while a condition
+    /// is being debounced or still present. Returns the newly latched faults.
+    pub fn on_status<SPI, P>(&mut self, status: reg::SPISTATUS, driver: &mut Tmc5130<SPI>, policy: &mut P) -> Result<Faults, Error<SPI::Error>>
+    where
+        SPI: SpiDevice<u8>,
+        P: FaultPolicy<SPI>,
+    {
+        let pending = self.counters.iter().any(|&count| count > 0);
+        if status.driver_error() || status.reset_flag() || pending {
+            self.poll(driver, policy)
+        } else {
+            Ok(Faults::NONE)
+        }
+    }
+
+    /// Feeds the faults present in one poll, returning the newly latched ones.
+    pub fn update(&mut self, present: Faults) -> Faults {
+        let mut raised = Faults::NONE;
+        for (fault, count) in Fault::ALL.into_iter().zip(self.counters.iter_mut()) {
+            if !present.contains(fault) {
+                *count = 0;
+                self.active.remove(fault);
+                continue;
+            }
+            *count = count.saturating_add(1);
+            if *count >= self.debounce && !self.active.contains(fault) {
+                self.active.insert(fault);
+                if !self.latched.contains(fault) {
+                    self.latched.insert(fault);
+                    raised.insert(fault);
+                }
+            }
+        }
+        raised
+    }
+
+    /// The faults present in the last poll, after debouncing.
+    pub fn active(&self) -> Faults {
+        self.active
+    }
+
+    /// The faults reported and not yet acknowledged.
+    pub fn latched(&self) -> Faults {
+        self.latched
+    }
+
+    /// Acknowledges faults, releasing their latch if they are no longer active. Returns the faults
+    /// still latched.
+    pub fn acknowledge(&mut self, faults: Faults) -> Faults {
+        for fault in faults.iter() {
+            if !self.active.contains(fault) {
+                self.latched.remove(fault);
+            }
+        }
+        self.latched
+    }
+
+    /// Clears the flags in `GSTAT` and resets the monitor.
+    ///
+    /// Clearing `GSTAT::drv_err` re-enables the driver after a shutdown, unless the policy disabled
+    /// it.
+    pub fn clear<SPI>(&mut self, driver: &mut Tmc5130<SPI>) -> Result<reg::SPISTATUS, Error<SPI::Error>>
+    where SPI: SpiDevice<u8>
+    {
+        let status = driver.write_register(reg::GSTAT::from(0b111))?;
+        *self = Self::new(self.debounce);
+        Ok(status)
+    }
+}
+
+impl Default for FaultMonitor {
+    fn default() -> Self {
+        Self::new(2)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write, Datagram};
+    use crate::reg::Address;
+
+    const OTPW: u32 = 1 << 26;
+    const S2GA: u32 = 1 << 27;
+
+    fn poll(drv_status: u32, gstat: u32) -> Vec<Datagram> {
+        let mut datagrams = read_register(Address::DRV_STATUS, drv_status).to_vec();
+        datagrams.extend(read_register(Address::GSTAT, gstat));
+        datagrams
+    }
+
+    #[test]
+    fn poll_reports_faults_of_both_registers() {
+        let (mut driver, mut spi) = driver(&poll(OTPW, 0b101));
+        let mut monitor = FaultMonitor::new(1);
+        let raised = monitor.poll(&mut driver, &mut ()).unwrap();
+        assert_eq!(raised, Faults::from(Fault::OvertemperaturePreWarning) | Fault::Undervoltage.into() | Fault::Reset.into());
+        spi.done();
+    }
+
+    #[test]
+    fn on_status_polls_only_when_needed() {
+        let mut datagrams = poll(0, 0);
+        datagrams.extend(poll(S2GA, 0));
+        // Still debouncing.
+        datagrams.extend(poll(S2GA, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let mut monitor = FaultMonitor::new(2);
+        assert!(monitor.on_status(reg::SPISTATUS(0), &mut driver, &mut ()).unwrap().is_empty());
+        // `driver_error`.
+        assert!(monitor.on_status(reg::SPISTATUS(0b10), &mut driver, &mut ()).unwrap().is_empty());
+        assert!(monitor.on_status(reg::SPISTATUS(0b10), &mut driver, &mut ()).unwrap().is_empty());
+        assert_eq!(monitor.on_status(reg::SPISTATUS(0), &mut driver, &mut ()).unwrap(), Fault::ShortPhaseA.into());
+        spi.done();
+    }
+
+    #[test]
+    fn disable_on_shutdown_clears_toff_of_chip_chopconf() {
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_toff(5);
+        chopconf.set_tbl(2);
+        chopconf.set_mres(4);
+        let mut disabled = chopconf;
+        disabled.set_toff(0);
+        let mut datagrams = poll(S2GA, 0);
+        datagrams.extend(read_register(Address::CHOPCONF, chopconf.0));
+        datagrams.push(write(Address::CHOPCONF, disabled.0, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let mut monitor = FaultMonitor::new(1);
+        assert_eq!(monitor.poll(&mut driver, &mut DisableOnShutdown).unwrap(), Fault::ShortPhaseA.into());
+        spi.done();
+    }
+
+    #[test]
+    fn disable_on_shutdown_ignores_warnings() {
+        let (mut driver, mut spi) = driver(&poll(OTPW, 0));
+        let mut monitor = FaultMonitor::new(1);
+        assert_eq!(monitor.poll(&mut driver, &mut DisableOnShutdown).unwrap(), Fault::OvertemperaturePreWarning.into());
+        spi.done();
+    }
+
+    #[test]
+    fn debounces_and_latches() {
+        let mut monitor = FaultMonitor::new(2);
+        let short = Faults::from(Fault::ShortPhaseA);
+        assert!(monitor.update(short).is_empty());
+        assert_eq!(monitor.update(short), short);
+        assert!(monitor.update(short).is_empty());
+
+        assert_eq!(monitor.acknowledge(short), short);
+        monitor.update(Faults::NONE);
+        assert_eq!(monitor.latched(), short);
+        assert!(monitor.acknowledge(short).is_empty());
+    }
+
+    #[test]
+    fn glitches_are_filtered() {
+        let mut monitor = FaultMonitor::new(3);
+        let open_load = Faults::from(Fault::OpenLoadPhaseB);
+        monitor.update(open_load);
+        monitor.update(open_load);
+        monitor.update(Faults::NONE);
+        assert!(monitor.update(open_load).is_empty());
+        assert!(monitor.latched().is_empty());
+    }
+}
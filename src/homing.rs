@@ -0,0 +1,343 @@
+//! Sensorless homing using StallGuard2.
+//!
+//! The motor is driven in velocity mode towards a mechanical end stop with `SW_MODE::sg_stop`
+//! enabled, so the ramp generator stops the motor as soon as StallGuard2 reports a stall. The stall
+//! is then released, and the stall position becomes the new home position.
+//!
+//! StallGuard2 only works in SpreadCycle and only while `TSTEP` is within `TCOOLTHRS`, so these are
+//! configured for the duration of the procedure and restored afterwards. Please refer to the
+//! StallGuard2 and sensorless homing sections of the TMC5130 datasheet when choosing `sgt`.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Direction, Error, Tmc5130};
+
+/// Tuning parameters of the sensorless homing procedure.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HomingConfig {
+    /// Direction in which the end stop is found.
+    pub direction: Direction,
+    /// StallGuard2 threshold `COOLCONF::sgt`, from -64 (most sensitive) to 63.
+    pub sgt: i8,
+    /// Enables the StallGuard2 filter `COOLCONF::sfilt`.
+    pub sfilt: bool,
+    /// The `TCOOLTHRS` value, stall detection is only active while `TSTEP` is below it.
+    pub tcoolthrs: u32,
+    /// Homing velocity written to `VMAX`.
+    pub velocity: u32,
+    /// Acceleration written to `AMAX`.
+    pub acceleration: u16,
+    /// The position assigned to the end stop once found.
+    pub home_position: i32,
+    /// Number of approaches before giving up.
+    pub attempts: u8,
+    /// Distance in microsteps to move away from the end stop before retrying.
+    pub retract_distance: u32,
+    /// Time allowed for each approach, in milliseconds.
+    pub timeout_ms: u32,
+    /// Delay between two polls of `RAMP_STAT`, in microseconds.
+    pub poll_interval_us: u32,
+}
+
+impl Default for HomingConfig {
+    fn default() -> Self {
+        Self {
+            direction: Direction::Negative,
+            sgt: 0,
+            sfilt: false,
+            tcoolthrs: 0xFFFFF,
+            velocity: 50_000,
+            acceleration: 1_000,
+            home_position: 0,
+            attempts: 3,
+            retract_distance: 3_200,
+            timeout_ms: 10_000,
+            poll_interval_us: 1_000,
+        }
+    }
+}
+
+/// The result of a successful homing.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct HomingReport {
+    /// The approach that found the end stop, starting at 1.
+    pub attempts: u8,
+    /// `XACTUAL` at the stall, in the coordinates used before homing.
+    pub stall_position: i32,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Homes the axis against a mechanical end stop, using StallGuard2 instead of a switch.
+    ///
+    /// A stall is only accepted once the ramp reached the homing velocity, earlier stalls are
+    /// treated as false triggers: the motor is retracted by `retract_distance` and the approach is
+    /// retried, as it is after a timeout. On success the stall is released, the stall position
+    /// becomes `home_position` and the motor is left in positioning mode at standstill.
+    ///
+    /// `GCONF`, `SW_MODE`, `COOLCONF` and `TCOOLTHRS` are restored to their previous values
+    /// whether or not homing succeeds, and an error restoring them is only returned if homing
+    /// itself succeeded. The soft limits are suspended during homing.
+    ///
+    /// Fails with [`Error::OutOfRange`] if `retract_distance` exceeds `i32::MAX`.
+    pub fn home_sensorless<D>(&mut self, config: &HomingConfig, delay: &mut D) -> Result<HomingReport, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        if i32::try_from(config.retract_distance).is_err() {
+            return Err(Error::OutOfRange);
+        }
+        let (_, gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        let (_, sw_mode) = self.read_register::<reg::SW_MODE>().map_err(Error::Spi)?;
+        let coolconf = *self.shadow.coolconf();
+        let tcoolthrs = *self.shadow.tcoolthrs();
+        // The end stop lies outside of any sensible soft limits.
+        let overridden = self.soft_limits_overridden;
+        self.soft_limits_overridden = true;
+
+        let result = self.home_sensorless_inner(config, gconf, sw_mode, coolconf, delay);
+
+        self.soft_limits_overridden = overridden;
+        let restored = self.write_register(gconf)
+            .and_then(|_| self.write_register(sw_mode))
+            .and_then(|_| self.write_register(coolconf))
+            .and_then(|_| self.write_register(tcoolthrs));
+        result.and_then(|report| restored.map(|_| report))
+    }
+
+    fn home_sensorless_inner<D>(&mut self, config: &HomingConfig, gconf: reg::GCONF, sw_mode: reg::SW_MODE, coolconf: reg::COOLCONF, delay: &mut D) -> Result<HomingReport, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        // StallGuard2 needs SpreadCycle.
+        let mut homing_gconf = gconf;
+        homing_gconf.set_en_pwm_mode(false);
+        self.write_register(homing_gconf)?;
+
+        let mut homing_coolconf = coolconf;
+        homing_coolconf.set_sgt(config.sgt);
+        homing_coolconf.set_sfilt(config.sfilt);
+        self.write_register(homing_coolconf)?;
+
+        let mut tcoolthrs = reg::TCOOLTHRS::default();
+        tcoolthrs.set(config.tcoolthrs);
+        self.write_register(tcoolthrs)?;
+
+        let mut homing_sw_mode = sw_mode;
+        homing_sw_mode.set_sg_stop(true);
+
+        for attempt in 1..=config.attempts {
+            // A stale stall event would stop the motor right away.
+            self.clear_stall_event()?;
+            self.write_register(homing_sw_mode)?;
+
+            let mut amax = reg::AMAX::default();
+            amax.set(config.acceleration);
+            self.write_register(amax)?;
+            let mut vmax = reg::VMAX::default();
+            vmax.set(config.velocity);
+            self.write_register(vmax)?;
+            self.write_register(config.direction.velocity_mode())?;
+
+            match self.wait_for_stop(delay, config)? {
+                Some(velocity_reached) => {
+                    // Reading the stall event releases the stall, so the motor must not have a
+                    // velocity to return to by then.
+                    self.write_register(reg::VMAX::default())?;
+                    let (_, ramp_stat) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+                    let (_, xactual) = self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+                    self.release_stall_stop(sw_mode)?;
+                    if ramp_stat.event_stop_sg() && velocity_reached {
+                        self.set_position(config.home_position)?;
+                        // The slack is taken up towards the end stop.
+                        self.set_backlash_direction(config.direction);
+                        return Ok(HomingReport { attempts: attempt, stall_position: xactual.get() });
+                    }
+                }
+                None => {
+                    self.write_register(sw_mode)?;
+                    self.stop_velocity_mode(delay, config)?;
+                }
+            }
+            self.retract(delay, config)?;
+        }
+        Err(Error::HomingFailed)
+    }
+
+    /// Sets the current position without moving the motor.
+    ///
+    /// The ramp generator is switched to hold mode while `XACTUAL` and `XTARGET` are written, and
+    /// left in positioning mode at standstill. As `XTARGET` equals `XACTUAL` this is not a move,
+    /// so it is not subject to the soft limits. Any backlash compensation offset is dropped, as
+    /// user and chip coordinates are aligned again.
+    pub fn set_position(&mut self, position: i32) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        self.reset_backlash_offset();
+        self.write_register(reg::RAMPMODE::HOLD)?;
+        let mut xactual = reg::XACTUAL::default();
+        xactual.set(position);
+        self.write_register(xactual)?;
+        self.write_raw(reg::Address::XTARGET, position as u32).map_err(Error::Spi)?;
+        self.write_register(reg::RAMPMODE::POSITIONING)
+    }
+
+    /// Polls `VACTUAL` until the motor stops after having moved, returning whether the datagram
+    /// status reported the homing velocity reached on the way, or `None` on timeout.
+    ///
+    /// `RAMP_STAT` is not polled, as reading `event_stop_sg` releases a stall stop and would let the
+    /// motor start again.
+    fn wait_for_stop<D>(&mut self, delay: &mut D, config: &HomingConfig) -> Result<Option<bool>, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let timeout_us = config.timeout_ms as u64 * 1000;
+        let mut elapsed_us = 0u64;
+        let mut moved = false;
+        let mut velocity_reached = false;
+        loop {
+            let (status, vactual) = self.read_register::<reg::VACTUAL>().map_err(Error::Spi)?;
+            if moved && vactual.get() == 0 {
+                return Ok(Some(velocity_reached));
+            }
+            moved |= vactual.get() != 0;
+            velocity_reached |= status.velocity_reached();
+            if elapsed_us >= timeout_us {
+                return Ok(None);
+            }
+            delay.delay_us(config.poll_interval_us);
+            elapsed_us += config.poll_interval_us.max(1) as u64;
+        }
+    }
+
+    /// Clears a pending `RAMP_STAT::event_stop_sg`, which is done by reading `RAMP_STAT`.
+    fn clear_stall_event(&mut self) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let (status, _) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+        Ok(status)
+    }
+
+    /// Releases the motor after a StallGuard2 stop.
+    ///
+    /// `VMAX` is zeroed first, so the motor does not start again once the stall is released. Stall
+    /// stop is disabled before `RAMP_STAT` is read to clear the event, so it can not trigger again
+    /// while the motor is at standstill, and the ramp generator is put on hold.
+    fn release_stall_stop(&mut self, sw_mode: reg::SW_MODE) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        self.write_register(reg::VMAX::default())?;
+        let mut released = sw_mode;
+        released.set_sg_stop(false);
+        self.write_register(released)?;
+        self.clear_stall_event()?;
+        self.write_register(reg::RAMPMODE::HOLD)
+    }
+
+    /// Decelerates a velocity mode move with `AMAX` and waits until the motor stands still.
+    fn stop_velocity_mode<D>(&mut self, delay: &mut D, config: &HomingConfig) -> Result<(), Error<SPI::Error>>
+    where D: DelayNs
+    {
+        self.write_register(reg::VMAX::default())?;
+        self.poll_ramp_stat(delay, config.poll_interval_us, config.timeout_ms, |ramp_stat| ramp_stat.vzero())?
+            .ok_or(Error::HomingFailed)?;
+        Ok(())
+    }
+
+    /// Moves away from the end stop before the next approach.
+    fn retract<D>(&mut self, delay: &mut D, config: &HomingConfig) -> Result<(), Error<SPI::Error>>
+    where D: DelayNs
+    {
+        if config.retract_distance == 0 {
+            return Ok(());
+        }
+        let (_, xactual) = self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        self.set_position(xactual.get())?;
+        let mut vmax = reg::VMAX::default();
+        vmax.set(config.velocity);
+        self.write_register(vmax)?;
+        let mut xtarget = reg::XTARGET::default();
+        // `home_sensorless` checked that the distance fits.
+        xtarget.set(xactual.get().wrapping_sub(config.direction.signum().wrapping_mul(config.retract_distance as i32)));
+        self.write_register(xtarget)?;
+        self.poll_ramp_stat(delay, config.poll_interval_us, config.timeout_ms, |ramp_stat| ramp_stat.position_reached())?
+            .ok_or(Error::HomingFailed)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+
+    use super::*;
+    use crate::mock_peripherals::{datagram, driver, read, read_register, write, Datagram};
+    use crate::reg::Address;
+
+    const GCONF: u32 = 0b100;
+    const SG_STOP: u32 = 1 << 10;
+
+    fn config() -> HomingConfig {
+        HomingConfig { sgt: 5, attempts: 1, retract_distance: 0, ..HomingConfig::default() }
+    }
+
+    /// Homing up to the stall stop, with the status of the first `VACTUAL` poll.
+    fn approach(status: u8, event_stop_sg: bool) -> Vec<Datagram> {
+        let mut datagrams = read_register(Address::GCONF, GCONF).to_vec();
+        datagrams.extend(read_register(Address::SW_MODE, 0));
+        datagrams.extend([write(Address::GCONF, 0, 0), write(Address::COOLCONF, 5 << 16, 0), write(Address::TCOOLTHRS, 0xFFFFF, 0)]);
+        datagrams.extend(read_register(Address::RAMP_STAT, 0));
+        datagrams.extend([
+            write(Address::SW_MODE, SG_STOP, 0),
+            write(Address::AMAX, 1_000, 0),
+            write(Address::VMAX, 50_000, 0),
+            write(Address::RAMPMODE, 2, 0),
+        ]);
+        datagrams.extend([read(Address::VACTUAL, 0), datagram(Address::VACTUAL as u8, 0, status, (-50_000i32) as u32)]);
+        datagrams.extend(read_register(Address::VACTUAL, 0));
+        // The stall event is only read with `VMAX` zero.
+        datagrams.push(write(Address::VMAX, 0, 0));
+        datagrams.extend(read_register(Address::RAMP_STAT, if event_stop_sg { 1 << 6 } else { 0 }));
+        datagrams.extend(read_register(Address::XACTUAL, (-5_000i32) as u32));
+        datagrams.extend([write(Address::VMAX, 0, 0), write(Address::SW_MODE, 0, 0)]);
+        datagrams.extend(read_register(Address::RAMP_STAT, 0));
+        datagrams.push(write(Address::RAMPMODE, 3, 0));
+        datagrams
+    }
+
+    fn restore() -> [Datagram; 4] {
+        [write(Address::GCONF, GCONF, 0), write(Address::SW_MODE, 0, 0), write(Address::COOLCONF, 0, 0), write(Address::TCOOLTHRS, 0, 0)]
+    }
+
+    #[test]
+    fn stall_at_velocity_sets_home() {
+        // `velocity_reached`.
+        let mut datagrams = approach(0x10, true);
+        datagrams.extend([
+            write(Address::RAMPMODE, 3, 0),
+            write(Address::XACTUAL, 0, 0),
+            write(Address::XTARGET, 0, 0),
+            write(Address::RAMPMODE, 0, 0),
+        ]);
+        datagrams.extend(restore());
+        let (mut driver, mut spi) = driver(&datagrams);
+        let report = driver.home_sensorless(&config(), &mut NoopDelay::new()).unwrap();
+        assert_eq!(report, HomingReport { attempts: 1, stall_position: -5_000 });
+        assert_eq!(driver.shadow().gconf().0, GCONF);
+        spi.done();
+    }
+
+    #[test]
+    fn stall_during_acceleration_is_rejected() {
+        let mut datagrams = approach(0, true);
+        datagrams.extend(restore());
+        let (mut driver, mut spi) = driver(&datagrams);
+        let result = driver.home_sensorless(&config(), &mut NoopDelay::new());
+        assert!(matches!(result, Err(Error::HomingFailed)));
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_retract_distances_beyond_i32() {
+        let (mut driver, mut spi) = driver(&[]);
+        let config = HomingConfig { retract_distance: 1 << 31, ..HomingConfig::default() };
+        assert!(matches!(driver.home_sensorless(&config, &mut NoopDelay::new()), Err(Error::OutOfRange)));
+        spi.done();
+    }
+}
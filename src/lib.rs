@@ -5,8 +5,36 @@ mod mock_peripherals;
 #[macro_use]
 extern crate bitfield;
 pub mod reg;
+pub mod homing;
+pub mod limits;
+pub mod backlash;
+pub mod compare;
+pub mod probe;
+pub mod direct;
+pub mod ramp;
+pub mod step_dir;
+pub mod current;
+pub mod chopper;
+pub mod stealth;
+pub mod coolstep;
+pub mod stallguard;
+pub mod dcstep;
+pub mod thresholds;
+pub mod mslut;
+pub mod microstepping;
+pub mod standstill;
+pub mod profile;
+pub mod fault;
+pub mod selftest;
+pub mod derating;
+pub mod telemetry;
+pub mod snapshot;
+pub mod stream;
+pub mod link;
+mod math;
 
 
+use embedded_hal::delay::DelayNs;
 use embedded_hal::spi::{ErrorType, Operation, SpiBus, SpiDevice};
 use core::borrow::BorrowMut;
 use crate::reg::{Address, SPISTATUS, State};
@@ -15,6 +43,81 @@ use crate::reg::{Address, SPISTATUS, State};
 pub struct Tmc5130<SPI> {
     /// The SPI interface used to communicate with the Tmc5130 chip.
     spi: SPI,
+    /// The last known state of every register.
+    ///
+    /// Updated on every write and read, this is the only source of truth for the write-only
+    /// registers, which is what allows single fields of them to be modified.
+    shadow: reg::Map,
+    /// Travel range enforced on `XTARGET` and velocity mode writes.
+    soft_limits: Option<limits::SoftLimits>,
+    /// Disables the soft limits for service moves while keeping them configured.
+    soft_limits_overridden: bool,
+    /// Backlash compensation state, if enabled.
+    backlash: Option<backlash::Backlash>,
+    /// Series of position compare triggers in progress, if any.
+    compare_schedule: Option<compare::CompareSchedule>,
+    /// Frequency of the chip clock fCLK, which all time and velocity units refer to.
+    clock_hz: u32,
+}
+
+/// Typical frequency of the internal clock, in Hz.
+pub const INTERNAL_CLOCK_HZ: u32 = 13_200_000;
+
+/// Errors returned by the higher level procedures of the driver.
+#[derive(Debug)]
+pub enum Error<E> {
+    /// The underlying SPI transaction failed.
+    Spi(E),
+    /// No stall was detected during sensorless homing within the allowed attempts.
+    HomingFailed,
+    /// An `XTARGET` write was rejected because it lies outside the soft limits.
+    OutsideSoftLimits,
+    /// The chip did not reach the expected state in time.
+    Timeout,
+    /// The operation requires the motor to stand still.
+    NotAtStandstill,
+    /// A value is outside of the range the chip accepts.
+    OutOfRange,
+    /// The ramp generator can not be used while direct coil current control is active.
+    DirectModeActive,
+    /// Coil currents can only be set in direct mode.
+    DirectModeInactive,
+    /// The operation is not supported with the current chip configuration.
+    Unsupported,
+    /// A tuning or calibration procedure did not converge.
+    NotConverged,
+    /// The CoolStep thresholds can not be expressed in `COOLCONF`.
+    CoolStep(coolstep::CoolStepError),
+    /// The SPI link check failed, see [`Tmc5130::verify_link`].
+    Link(link::LinkError),
+}
+
+/// Direction of travel along the motor axis.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Direction {
+    /// Towards decreasing `XACTUAL`, the side of the left reference switch.
+    Negative,
+    /// Towards increasing `XACTUAL`, the side of the right reference switch.
+    Positive,
+}
+
+impl Direction {
+    /// The velocity mode `RAMPMODE` that moves the motor in this direction.
+    pub fn velocity_mode(self) -> reg::RAMPMODE {
+        match self {
+            Direction::Negative => reg::RAMPMODE::VELOCITY_NEGATIVE,
+            Direction::Positive => reg::RAMPMODE::VELOCITY_POSITIVE,
+        }
+    }
+
+    /// The sign of a move in this direction, `-1` or `1`.
+    pub fn signum(self) -> i32 {
+        match self {
+            Direction::Negative => -1,
+            Direction::Positive => 1,
+        }
+    }
 }
 pub enum Action<'a> {
     read(&'a mut State),
@@ -39,9 +142,29 @@ where
     ) -> Self{
         let mut instance = Self {
             spi,
+            shadow: reg::Map::default(),
+            soft_limits: None,
+            soft_limits_overridden: false,
+            backlash: None,
+            compare_schedule: None,
+            clock_hz: INTERNAL_CLOCK_HZ,
         };
         instance
     }
+    /// Sets the frequency of the clock the chip runs on, if it is not the internal clock.
+    ///
+    /// It is only used to convert between physical units and register values.
+    pub fn set_clock_frequency(&mut self, clock_hz: u32) {
+        self.clock_hz = clock_hz;
+    }
+    /// The frequency of the chip clock, in Hz.
+    pub fn clock_frequency(&self) -> u32 {
+        self.clock_hz
+    }
+    /// The last known state of all registers, as written to or read from the chip.
+    pub fn shadow(&self) -> &reg::Map {
+        &self.shadow
+    }
     fn as_u32_be(array: &[u8]) -> u32 {
         ((array[0] as u32) << 24) +
             ((array[1] as u32) << 16) +
@@ -59,11 +182,28 @@ where
         let mut address_buffer = [address;1];
         self.spi.transaction(&mut [Operation::TransferInPlace(address_buffer.borrow_mut()), Operation::TransferInPlace(data_buffer.borrow_mut())])?;
         let status = reg::SPISTATUS(address_buffer[0]);
+        let data = u32::from_be_bytes(data_buffer);
+        self.shadow.set_state(State::from_addr_and_data(R::ADDRESS, data));
 
-        Ok((status,R::from(u32::from_be_bytes(data_buffer))))
+        Ok((status,R::from(self.backlash_read(R::ADDRESS, data))))
     }
-    pub fn bulk_register_action(&mut self, actions: &mut [Action]) -> Result<reg::SPISTATUS, <SPI as ErrorType>::Error>
+    pub fn bulk_register_action(&mut self, actions: &mut [Action]) -> Result<reg::SPISTATUS, Error<<SPI as ErrorType>::Error>>
     {
+        // Reject the whole batch before anything is sent if a write would be refused, following
+        // the direct mode through the `GCONF` writes of the batch.
+        let mut direct_mode = self.direct_mode();
+        for action in actions.iter() {
+            if let Action::write(state) = action {
+                if !direct::ramp_write_allowed(direct_mode, state.addr()) {
+                    return Err(Error::DirectModeActive);
+                }
+                self.soft_limit_write(state.addr(), (**state).into())?;
+                if let State::GCONF(gconf) = **state {
+                    direct_mode = gconf.direct_mode();
+                }
+            }
+        }
+
         let mut result = reg::SPISTATUS(0);
         let act_len = actions.len();
         let mut extra_transmission = false;
@@ -72,6 +212,7 @@ where
         }
 
         for i in 0..act_len {
+            let mut prelude = None;
             let (mut address_buf,mut data_buf)  = match actions[i].borrow_mut() {
                 Action::read(state) => {
                     //defmt::info!("Read address: {}", (state.reg()::ADDRESS) as u8);
@@ -81,15 +222,25 @@ where
                     ([state.addr() as u8 & ! Self::RW_BIT;1], state_num.to_be_bytes())
                 }
                 Action::write(state) => {
-                    let state_num: u32 = (*(*state)).into();
+                    let (state_num, extra) = self.translate_write(state.addr(), (*(*state)).into())?;
+                    prelude = extra;
+                    self.shadow.set_state(State::from_addr_and_data(state.addr(), state_num));
                     ([state.addr() as u8 | Self::RW_BIT;1], state_num.to_be_bytes())
                 }
             };
+            // The reply carrying the data of the previous read is the one to the first datagram sent.
+            let mut reply = None;
+            if let Some((addr, data)) = prelude {
+                let (_, prelude_reply) = self.write_raw(addr, data).map_err(Error::Spi)?;
+                reply = Some(prelude_reply);
+            }
             result = reg::SPISTATUS(address_buf[0]);
-            self.spi.transaction(&mut [Operation::TransferInPlace(address_buf.borrow_mut()), Operation::TransferInPlace(data_buf.borrow_mut())])?;
+            self.spi.transaction(&mut [Operation::TransferInPlace(address_buf.borrow_mut()), Operation::TransferInPlace(data_buf.borrow_mut())]).map_err(Error::Spi)?;
+            let reply = reply.unwrap_or(u32::from_be_bytes(data_buf));
             if i > 0 {
                 if let Action::read(last_state) = &mut actions[i-1] {
-                    **last_state= reg::State::from_addr_and_data(last_state.addr(), u32::from_be_bytes(data_buf));
+                    self.shadow.set_state(reg::State::from_addr_and_data(last_state.addr(), reply));
+                    **last_state= reg::State::from_addr_and_data(last_state.addr(), self.backlash_read(last_state.addr(), reply));
                 }
             }
         }
@@ -97,228 +248,127 @@ where
             // Last transaction was a read. we need another read to get the data out.
             if let Action::read(last_state) = &mut actions[act_len-1] {
                 let state_num: u32 = (*(*last_state)).into();
-                let (mut address_buf, mut data_buf) = ([last_state.addr() as u8 | Self::RW_BIT; 1], state_num.to_be_bytes());
-                self.spi.transaction(&mut [Operation::TransferInPlace(address_buf.borrow_mut()), Operation::TransferInPlace(data_buf.borrow_mut())])?;
-                **last_state = State::from_addr_and_data(last_state.addr(), u32::from_be_bytes(data_buf));
+                let (mut address_buf, mut data_buf) = ([last_state.addr() as u8 & !Self::RW_BIT; 1], state_num.to_be_bytes());
+                self.spi.transaction(&mut [Operation::TransferInPlace(address_buf.borrow_mut()), Operation::TransferInPlace(data_buf.borrow_mut())]).map_err(Error::Spi)?;
+                let data = u32::from_be_bytes(data_buf);
+                self.shadow.set_state(State::from_addr_and_data(last_state.addr(), data));
+                **last_state = State::from_addr_and_data(last_state.addr(), self.backlash_read(last_state.addr(), data));
                 result = reg::SPISTATUS(address_buf[0]);
             }
         }
         Ok(result)
     }
-    pub fn write_register<R>(&mut self, register:R) -> Result<reg::SPISTATUS, <SPI as ErrorType>::Error>
+    /// Writes a register.
+    ///
+    /// Writes to `XTARGET` and `RAMPMODE` are subject to the soft limits, see
+    /// [`Tmc5130::set_soft_limits`], and positions are offset by the backlash compensation, see
+    /// [`Tmc5130::set_backlash_compensation`].
+    pub fn write_register<R>(&mut self, register:R) -> Result<reg::SPISTATUS, Error<<SPI as ErrorType>::Error>>
         where R: reg::WritableRegister
     {
-        let address = R::ADDRESS as u8 | Self::RW_BIT;
-        let mut address_buffer = [address;1];
-        let mut data_buffer = (<R as Into<u32>>::into(register)).to_be_bytes();
+        let (data, prelude) = self.translate_write(R::ADDRESS, register.into())?;
+        if let Some((addr, prelude_data)) = prelude {
+            self.write_raw(addr, prelude_data).map_err(Error::Spi)?;
+        }
+        let (status, _) = self.write_raw(R::ADDRESS, data).map_err(Error::Spi)?;
+        Ok(status)
+    }
+    /// Turns a register write in user coordinates into the writes sent to the chip.
+    ///
+    /// Ramp generator writes are refused in direct mode. The soft limits apply next, as they are
+    /// expressed in user coordinates, then the backlash compensation maps positions to chip
+    /// coordinates.
+    fn translate_write(&mut self, addr: Address, data: u32) -> Result<(u32, Option<limits::RawWrite>), Error<<SPI as ErrorType>::Error>> {
+        self.direct_mode_write_guard(addr)?;
+        let (data, prelude) = self.soft_limit_write(addr, data)?;
+        let prelude = prelude.map(|(prelude_addr, prelude_data)| (prelude_addr, self.backlash_write(prelude_addr, prelude_data)));
+        Ok((self.backlash_write(addr, data), prelude))
+    }
+    /// Sends a single write datagram, bypassing the soft limits and backlash compensation.
+    ///
+    /// Returns the status and the data of the reply.
+    fn write_raw(&mut self, addr: Address, data: u32) -> Result<(reg::SPISTATUS, u32), <SPI as ErrorType>::Error> {
+        let mut address_buffer = [addr as u8 | Self::RW_BIT;1];
+        let mut data_buffer = data.to_be_bytes();
+        self.spi.transaction(&mut [Operation::TransferInPlace(address_buffer.borrow_mut()), Operation::TransferInPlace(data_buffer.borrow_mut())])?;
+        self.shadow.set_state(State::from_addr_and_data(addr, data));
+        Ok((reg::SPISTATUS(address_buffer[0]), u32::from_be_bytes(data_buffer)))
+    }
+    /// Sends a read request for `addr`, returning the status and the data of the previous request.
+    fn read_raw(&mut self, addr: Address) -> Result<(reg::SPISTATUS, u32), <SPI as ErrorType>::Error> {
+        let mut address_buffer = [addr as u8 & !Self::RW_BIT;1];
+        let mut data_buffer = [0u8;4];
         self.spi.transaction(&mut [Operation::TransferInPlace(address_buffer.borrow_mut()), Operation::TransferInPlace(data_buffer.borrow_mut())])?;
-        Ok(reg::SPISTATUS(address_buffer[0]))
+        Ok((reg::SPISTATUS(address_buffer[0]), u32::from_be_bytes(data_buffer)))
+    }
+    /// Polls `RAMP_STAT` until `done` returns true for it or `timeout_ms` elapses.
+    ///
+    /// Returns the `RAMP_STAT` that satisfied `done`, or `None` on timeout.
+    pub(crate) fn poll_ramp_stat<D, F>(&mut self, delay: &mut D, poll_interval_us: u32, timeout_ms: u32, mut done: F) -> Result<Option<reg::RAMP_STAT>, Error<SPI::Error>>
+    where D: DelayNs,
+          F: FnMut(reg::RAMP_STAT) -> bool
+    {
+        let timeout_us = timeout_ms as u64 * 1000;
+        let mut elapsed_us = 0u64;
+        loop {
+            let (_, ramp_stat) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+            if done(ramp_stat) {
+                return Ok(Some(ramp_stat));
+            }
+            if elapsed_us >= timeout_us {
+                return Ok(None);
+            }
+            delay.delay_us(poll_interval_us);
+            elapsed_us += poll_interval_us.max(1) as u64;
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::mock_peripherals::{MockOperation, MockSimpleHalSpiDevice};
-
-    // Predefined control words for testing
-    const CTRL_WORD_X_NO_IRQ: u8 = 0b11010011;
-    const CTRL_WORD_Y_NO_IRQ: u8 = 0b10010011;
-    const CTRL_WORD_Z1_NO_IRQ: u8 = 0b10110011;
-    const CTRL_WORD_Z2_NO_IRQ: u8 = 0b11000011;
+    use crate::mock_peripherals::{datagram, driver, read, write};
 
-    const CTRL_WORD_X_IRQ: u8 = 0b11010000;
-    const CTRL_WORD_Y_IRQ: u8 = 0b10010000;
-    const CTRL_WORD_Z1_IRQ: u8 = 0b10110000;
-    const CTRL_WORD_Z2_IRQ: u8 = 0b11000000;
-
-    //Helper function to assert SPI operations
-    fn assert_spi_operations<Word: std::cmp::PartialEq + std::fmt::Debug + std::marker::Copy>(
-        ops: &mut [Operation<'_, Word>],
-        expected_ops: &[MockOperation<'_, Word>],
-    ) {
-        assert_eq!(ops.len(), expected_ops.len());
-        let both_ops = ops.iter_mut().zip(expected_ops.iter());
-        for (op, expected_op) in both_ops {
-            match expected_op {
-                MockOperation::Read(expected_read_buf) => {
-                    match op {
-                        // In case of a reading operation, we copy the expected values into the buffer.
-                        Operation::Read(op_read_buf) => {
-                            assert_eq!(op_read_buf.len(), expected_read_buf.len());
-                            for i in 0..op_read_buf.len() {
-                                op_read_buf[i] = expected_read_buf[i];
-                            }
-                        }
-                        _ => assert!(false),
-                    }
-                }
-                MockOperation::Write(expected_write_buf) => {
-                    match op {
-                        // In case of a Writing operation, the value of the buffer is compared against the value of the expected buffer.
-                        Operation::Write(op_write_buf) => {
-                            assert_eq!(op_write_buf, expected_write_buf);
-                        }
-                        _ => assert!(false),
-                    }
-                }
-            }
-        }
-    }
-
-    static X_TOUCH_VALUE: u16 = 100;
-    static Y_TOUCH_VALUE: u16 = 100;
-    static Z1_TOUCH_VALUE: u16 = 5;
-    static Z2_TOUCH_VALUE: u16 = 2053;
-
-    static INIT_RETURN_BUF: [u8; 2] = [0, 0];
-    static READ_X_RETURN_BUF: [u8; 2] = [(X_TOUCH_VALUE >> 5) as u8, (X_TOUCH_VALUE << 3) as u8];
-    static READ_Y_RETURN_BUF: [u8; 2] = [(Y_TOUCH_VALUE >> 5) as u8, (Y_TOUCH_VALUE << 3) as u8];
-    static READ_Z1_RETURN_BUF: [u8; 2] = [(Z1_TOUCH_VALUE >> 5) as u8, (Z1_TOUCH_VALUE << 3) as u8];
-    static READ_Z2_RETURN_BUF: [u8; 2] = [(Z2_TOUCH_VALUE >> 5) as u8, (Z2_TOUCH_VALUE << 3) as u8];
     #[test]
-    fn test_get_touch_no_irq() {
-        let expected_ops_init = [
-            MockOperation::Write(&[CTRL_WORD_X_NO_IRQ]),
-            MockOperation::Read(&INIT_RETURN_BUF),
-        ];
-        let expected_ops_x = [
-            MockOperation::Write(&[CTRL_WORD_X_NO_IRQ]),
-            MockOperation::Read(&READ_X_RETURN_BUF),
-        ];
-        let expected_ops_y = [
-            MockOperation::Write(&[CTRL_WORD_Y_NO_IRQ]),
-            MockOperation::Read(&READ_Y_RETURN_BUF),
-        ];
-        let expected_ops_z1 = [
-            MockOperation::Write(&[CTRL_WORD_Z1_NO_IRQ]),
-            MockOperation::Read(&READ_Z1_RETURN_BUF),
-        ];
-        let expected_ops_z2 = [
-            MockOperation::Write(&[CTRL_WORD_Z2_NO_IRQ]),
-            MockOperation::Read(&READ_Z2_RETURN_BUF),
-        ];
-        let mut mock_spi_dev = MockSimpleHalSpiDevice::new();
-
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_init);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_x);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_y);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_z1);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_z2);
-                Ok(())
-            });
-        let mut test_driver =
-            Tmc5130::new(mock_spi_dev).expect("Could not create driver");
-        let mut chopconf = reg::CHOPCONF::default();
+    fn write_register_sends_one_datagram() {
+        let mut chopconf = reg::CHOPCONF::from(0);
         chopconf.set_toff(3);
         chopconf.set_hstrt(4);
         chopconf.set_hend(1);
         chopconf.set_tbl(2);
-        chopconf.set_chm(false);
-        test_driver.write_register(chopconf).unwrap();
-
+        let (mut driver, mut spi) = driver(&[datagram(0xEC, chopconf.0, 0b0000_1000, 0)]);
+        let status = driver.write_register(chopconf).unwrap();
+        assert!(status.standstill());
+        assert_eq!(driver.shadow().chopconf(), &chopconf);
+        spi.done();
     }
 
     #[test]
-    fn test_get_touch_irq() {
-        let expected_ops_init = [
-            MockOperation::Write(&[CTRL_WORD_X_NO_IRQ]),
-            MockOperation::Read(&INIT_RETURN_BUF),
-        ];
-        let expected_ops_irq_set = [
-            MockOperation::Write(&[CTRL_WORD_X_IRQ]),
-            MockOperation::Read(&INIT_RETURN_BUF),
-        ];
-
-        let expected_ops_x = [
-            MockOperation::Write(&[CTRL_WORD_X_IRQ]),
-            MockOperation::Read(&READ_X_RETURN_BUF),
-        ];
-        let expected_ops_y = [
-            MockOperation::Write(&[CTRL_WORD_Y_IRQ]),
-            MockOperation::Read(&READ_Y_RETURN_BUF),
-        ];
-        let expected_ops_z1 = [
-            MockOperation::Write(&[CTRL_WORD_Z1_IRQ]),
-            MockOperation::Read(&READ_Z1_RETURN_BUF),
-        ];
-        let expected_ops_z2 = [
-            MockOperation::Write(&[CTRL_WORD_Z2_IRQ]),
-            MockOperation::Read(&READ_Z2_RETURN_BUF),
-        ];
-        let mut mock_spi_dev = MockSimpleHalSpiDevice::new();
+    fn read_register_takes_data_from_second_reply() {
+        // The second request repeats the data of the first reply, which a read ignores.
+        let (mut driver, mut spi) = driver(&[
+            read(Address::XACTUAL, 0xDEAD_BEEF),
+            datagram(Address::XACTUAL as u8, 0xDEAD_BEEF, 0, 1234),
+        ]);
+        let (_, xactual) = driver.read_register::<reg::XACTUAL>().unwrap();
+        assert_eq!(xactual.get(), 1234);
+        spi.done();
+    }
 
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_init);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_irq_set);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_x);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_y);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_z1);
-                Ok(())
-            });
-        mock_spi_dev
-            .expect_transaction()
-            .times(1)
-            .returning(move |operations| {
-                assert_spi_operations(operations, &expected_ops_z2);
-                Ok(())
-            });
-        let mut test_driver =
-            Tmc5130::new(mock_spi_dev).expect("Could not create driver");
+    #[test]
+    fn bulk_trailing_read_is_not_a_write() {
+        let mut vmax = reg::VMAX::default();
+        vmax.set(1000);
+        let vmax = State::from(vmax);
+        let mut xactual = State::from(reg::XACTUAL::default());
+        let (mut driver, mut spi) = driver(&[
+            write(Address::VMAX, 1000, 0),
+            read(Address::XACTUAL, 0),
+            // The extra datagram collecting the last read has the RW bit clear.
+            read(Address::XACTUAL, 42),
+        ]);
+        driver.bulk_register_action(&mut [Action::write(&vmax), Action::read(&mut xactual)]).unwrap();
+        assert_eq!(xactual, State::from_addr_and_data(Address::XACTUAL, 42));
+        spi.done();
     }
 }
@@ -0,0 +1,199 @@
+//! Soft travel limits enforced by the driver.
+//!
+//! Once configured, every write of `XTARGET` is checked against the allowed range, whether it comes
+//! from [`Tmc5130::write_register`], [`Tmc5130::bulk_register_action`] or one of the higher level
+//! procedures. Velocity mode has no target the chip could stop at, so switching `RAMPMODE` to a
+//! velocity mode is turned into a positioning move to the limit in that direction instead. The
+//! motor then runs at `VMAX` and decelerates with `DMAX` and `D1` to stop on the boundary.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address};
+use crate::{Error, Tmc5130};
+
+/// What to do with an `XTARGET` write outside the soft limits.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LimitPolicy {
+    /// Fail the write with [`Error::OutsideSoftLimits`].
+    Reject,
+    /// Write the nearest limit instead.
+    Clamp,
+}
+
+/// The allowed travel range, in microsteps.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SoftLimits {
+    /// Lowest allowed `XTARGET`.
+    pub min: i32,
+    /// Highest allowed `XTARGET`.
+    pub max: i32,
+    /// How out of range targets are handled.
+    pub policy: LimitPolicy,
+}
+
+impl SoftLimits {
+    /// Applies the limits to a target position.
+    ///
+    /// Returns `None` if the target is out of range and the policy is to reject it.
+    pub fn apply(&self, target: i32) -> Option<i32> {
+        if (self.min..=self.max).contains(&target) {
+            return Some(target);
+        }
+        match self.policy {
+            LimitPolicy::Reject => None,
+            LimitPolicy::Clamp => Some(target.clamp(self.min, self.max)),
+        }
+    }
+}
+
+/// A register address and the data to write to it.
+pub(crate) type RawWrite = (Address, u32);
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Configures the soft limits, or removes them with `None`.
+    ///
+    /// Fails with [`Error::OutOfRange`] if `min` is greater than `max`, keeping the previous limits.
+    pub fn set_soft_limits(&mut self, limits: Option<SoftLimits>) -> Result<(), Error<SPI::Error>> {
+        if limits.is_some_and(|limits| limits.min > limits.max) {
+            return Err(Error::OutOfRange);
+        }
+        self.soft_limits = limits;
+        Ok(())
+    }
+
+    /// The configured soft limits.
+    pub fn soft_limits(&self) -> Option<SoftLimits> {
+        self.soft_limits
+    }
+
+    /// Suspends the soft limits while `overridden` is true, for service and setup moves.
+    ///
+    /// The configured limits are kept and apply again once the override is lifted.
+    pub fn set_soft_limits_override(&mut self, overridden: bool) {
+        self.soft_limits_overridden = overridden;
+    }
+
+    /// Whether the soft limits are currently suspended.
+    pub fn soft_limits_overridden(&self) -> bool {
+        self.soft_limits_overridden
+    }
+
+    /// Applies the soft limits to a register write.
+    ///
+    /// Returns the data to write in its place, and an additional write that has to be sent before
+    /// it. Only `XTARGET` and `RAMPMODE` writes are affected.
+    pub(crate) fn soft_limit_write(&self, addr: Address, data: u32) -> Result<(u32, Option<RawWrite>), Error<SPI::Error>> {
+        let limits = match self.soft_limits {
+            Some(limits) if !self.soft_limits_overridden => limits,
+            _ => return Ok((data, None)),
+        };
+        match addr {
+            Address::XTARGET => {
+                let target = limits.apply(data as i32).ok_or(Error::OutsideSoftLimits)?;
+                Ok((target as u32, None))
+            }
+            Address::RAMPMODE => {
+                let boundary = match reg::RAMPMODE(data).get() {
+                    1 => limits.max,
+                    2 => limits.min,
+                    _ => return Ok((data, None)),
+                };
+                Ok((reg::RAMPMODE::POSITIONING.into(), Some((Address::XTARGET, boundary as u32))))
+            }
+            _ => Ok((data, None)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, write};
+    use crate::Action;
+
+    const LIMITS: SoftLimits = SoftLimits { min: -1_000, max: 1_000, policy: LimitPolicy::Reject };
+
+    fn xtarget(position: i32) -> reg::XTARGET {
+        let mut xtarget = reg::XTARGET::default();
+        xtarget.set(position);
+        xtarget
+    }
+
+    #[test]
+    fn rejects_targets_outside() {
+        let (mut driver, mut spi) = driver(&[write(Address::XTARGET, 1_000, 0)]);
+        driver.set_soft_limits(Some(LIMITS)).unwrap();
+        driver.write_register(xtarget(1_000)).unwrap();
+        assert!(matches!(driver.write_register(xtarget(1_001)), Err(Error::OutsideSoftLimits)));
+        assert_eq!(driver.shadow().xtarget().get(), 1_000);
+        spi.done();
+    }
+
+    #[test]
+    fn clamps_targets_outside() {
+        let (mut driver, mut spi) = driver(&[write(Address::XTARGET, -1_000i32 as u32, 0)]);
+        driver.set_soft_limits(Some(SoftLimits { policy: LimitPolicy::Clamp, ..LIMITS })).unwrap();
+        driver.write_register(xtarget(-5_000)).unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn velocity_mode_moves_to_limit() {
+        let (mut driver, mut spi) = driver(&[
+            write(Address::XTARGET, -1_000i32 as u32, 0),
+            write(Address::RAMPMODE, 0, 0),
+        ]);
+        driver.set_soft_limits(Some(LIMITS)).unwrap();
+        driver.write_register(reg::RAMPMODE::VELOCITY_NEGATIVE).unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn rejected_batch_is_not_sent() {
+        let (mut driver, mut spi) = driver(&[]);
+        driver.set_soft_limits(Some(LIMITS)).unwrap();
+        let vmax = reg::State::from(reg::VMAX::default());
+        let target = reg::State::from(xtarget(2_000));
+        let result = driver.bulk_register_action(&mut [Action::write(&vmax), Action::write(&target)]);
+        assert!(matches!(result, Err(Error::OutsideSoftLimits)));
+        spi.done();
+    }
+
+    #[test]
+    fn batch_enabling_direct_mode_is_not_sent() {
+        let (mut driver, mut spi) = driver(&[]);
+        driver.set_soft_limits(Some(LIMITS)).unwrap();
+        let mut gconf = reg::GCONF::default();
+        gconf.set_direct_mode(true);
+        let gconf = reg::State::from(gconf);
+        let target = reg::State::from(xtarget(500));
+        let result = driver.bulk_register_action(&mut [Action::write(&gconf), Action::write(&target)]);
+        assert!(matches!(result, Err(Error::DirectModeActive)));
+        assert!(!driver.direct_mode());
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_inverted_limits() {
+        let (mut driver, mut spi) = driver(&[]);
+        driver.set_soft_limits(Some(LIMITS)).unwrap();
+        let inverted = SoftLimits { min: 1, max: 0, ..LIMITS };
+        assert!(matches!(driver.set_soft_limits(Some(inverted)), Err(Error::OutOfRange)));
+        assert_eq!(driver.soft_limits(), Some(LIMITS));
+        spi.done();
+    }
+
+    #[test]
+    fn override_suspends_limits() {
+        let (mut driver, mut spi) = driver(&[write(Address::XTARGET, 2_000, 0)]);
+        driver.set_soft_limits(Some(LIMITS)).unwrap();
+        driver.set_soft_limits_override(true);
+        driver.write_register(xtarget(2_000)).unwrap();
+        spi.done();
+    }
+}
@@ -0,0 +1,163 @@
+//! SPI link check and chip identification.
+//!
+//! With broken wiring the chip does not answer, and the driver reads all zeros or all ones
+//! depending on whether MISO floats low or high. [`Tmc5130::verify_link`] tells such a link apart
+//! from a working one by the version in `IOIN` and by writing test patterns to the DIAG output
+//! configuration in `GCONF` and reading them back.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address};
+use crate::{Error, Tmc5130};
+
+/// The version in `IOIN` of the TMC5130.
+pub const CHIP_VERSION: u8 = 0x11;
+
+/// The `GCONF` bits the test patterns are written to, the DIAG0 and DIAG1 routing and output type
+/// and `small_hysteresis`, none of which affect the motor driven over SPI.
+const PATTERN_MASK: u32 = 0x7FE0;
+
+/// Test patterns for the bits of [`PATTERN_MASK`], setting each of them once at each level.
+const PATTERNS: [u32; 2] = [0x2AA0, 0x5540];
+
+/// Reasons the SPI link check fails.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum LinkError {
+    /// Status and data read back as all ones or all zeros, MISO is stuck at the given level.
+    StuckMiso { high: bool },
+    /// `IOIN` reports a version other than [`CHIP_VERSION`].
+    WrongVersion { version: u8 },
+    /// A test pattern written to `GCONF` read back differently.
+    PatternMismatch { written: u32, read: u32 },
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Checks the SPI link and identifies the chip, returning `IOIN`.
+    ///
+    /// Fails with [`Error::Link`] if MISO is stuck, the version is not the one of the TMC5130 or a
+    /// test pattern does not read back. The patterns only change the DIAG output bits of `GCONF`,
+    /// so the DIAG0 and DIAG1 outputs may toggle for a few datagrams, after which `GCONF` is
+    /// restored to the value read from the chip, in the shadow as well.
+    pub fn verify_link(&mut self) -> Result<reg::IOIN, Error<SPI::Error>> {
+        self.read_raw(Address::IOIN).map_err(Error::Spi)?;
+        let (status, data) = self.read_raw(Address::GCONF).map_err(Error::Spi)?;
+        match (status.0, data) {
+            (0xFF, u32::MAX) => return Err(Error::Link(LinkError::StuckMiso { high: true })),
+            (0x00, 0) => return Err(Error::Link(LinkError::StuckMiso { high: false })),
+            _ => {}
+        }
+        let ioin = reg::IOIN::from(data);
+        if ioin.version() as u8 != CHIP_VERSION {
+            return Err(Error::Link(LinkError::WrongVersion { version: ioin.version() as u8 }));
+        }
+
+        let (_, gconf) = self.read_raw(Address::GCONF).map_err(Error::Spi)?;
+        let patterns = PATTERNS.map(|pattern| gconf & !PATTERN_MASK | pattern);
+        self.write_raw(Address::GCONF, patterns[0]).map_err(Error::Spi)?;
+        let result = self.verify_patterns(&patterns);
+        // The restoring write returns the read back of the last pattern.
+        let (_, read) = self.write_raw(Address::GCONF, gconf).map_err(Error::Spi)?;
+        let written = result?;
+        if read != written {
+            return Err(Error::Link(LinkError::PatternMismatch { written, read }));
+        }
+        Ok(ioin)
+    }
+
+    /// Reads back each pattern after the first one was written, writing the next one with the
+    /// datagram that returns it. Returns the last pattern, whose read back is still pending.
+    fn verify_patterns(&mut self, patterns: &[u32]) -> Result<u32, Error<SPI::Error>> {
+        self.read_raw(Address::GCONF).map_err(Error::Spi)?;
+        for pair in patterns.windows(2) {
+            let (_, read) = self.write_raw(Address::GCONF, pair[1]).map_err(Error::Spi)?;
+            if read != pair[0] {
+                return Err(Error::Link(LinkError::PatternMismatch { written: pair[0], read }));
+            }
+            self.read_raw(Address::GCONF).map_err(Error::Spi)?;
+        }
+        Ok(patterns[patterns.len() - 1])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{datagram, driver, read, write, Datagram};
+
+    const IOIN: u32 = (CHIP_VERSION as u32) << 24 | 0x40;
+    // `en_pwm_mode` and `diag0_stall`.
+    const GCONF: u32 = 0x0_0084;
+
+    fn pattern(index: usize) -> u32 {
+        GCONF & !PATTERN_MASK | PATTERNS[index]
+    }
+
+    fn identification(status: u8, ioin: u32) -> [Datagram; 2] {
+        [read(Address::IOIN, 0), datagram(Address::GCONF as u8, 0, status, ioin)]
+    }
+
+    fn pattern_test(first_read_back: u32) -> [Datagram; 5] {
+        [
+            read(Address::GCONF, GCONF),
+            write(Address::GCONF, pattern(0), 0),
+            read(Address::GCONF, 0),
+            write(Address::GCONF, pattern(1), first_read_back),
+            read(Address::GCONF, 0),
+        ]
+    }
+
+    #[test]
+    fn identifies_chip_and_restores_gconf() {
+        let mut datagrams = identification(0x08, IOIN).to_vec();
+        datagrams.extend(pattern_test(pattern(0)));
+        datagrams.push(write(Address::GCONF, GCONF, pattern(1)));
+        let (mut driver, mut spi) = driver(&datagrams);
+        assert_eq!(driver.verify_link().unwrap().version() as u8, CHIP_VERSION);
+        assert_eq!(driver.shadow().gconf().0, GCONF);
+        spi.done();
+    }
+
+    #[test]
+    fn patterns_only_touch_diag_outputs() {
+        for index in 0..PATTERNS.len() {
+            assert_eq!(pattern(index) & !PATTERN_MASK, GCONF & !PATTERN_MASK);
+        }
+        assert_eq!(PATTERNS[0] | PATTERNS[1], PATTERN_MASK);
+        assert_eq!(PATTERNS[0] & PATTERNS[1], 0);
+    }
+
+    #[test]
+    fn detects_stuck_miso() {
+        for (status, data, high) in [(0xFF, u32::MAX, true), (0x00, 0, false)] {
+            let (mut driver, mut spi) = driver(&identification(status, data));
+            let error = driver.verify_link();
+            assert!(matches!(error, Err(Error::Link(LinkError::StuckMiso { high: stuck })) if stuck == high));
+            spi.done();
+        }
+    }
+
+    #[test]
+    fn rejects_other_chips() {
+        let (mut driver, mut spi) = driver(&identification(0x08, 0x30 << 24));
+        assert!(matches!(driver.verify_link(), Err(Error::Link(LinkError::WrongVersion { version: 0x30 }))));
+        spi.done();
+    }
+
+    #[test]
+    fn pattern_mismatch_still_restores_gconf() {
+        let mut datagrams = identification(0x08, IOIN).to_vec();
+        // The read back of the first pattern differs, the second one is not read back.
+        datagrams.extend(pattern_test(pattern(0) ^ 0x20).into_iter().take(4));
+        datagrams.push(write(Address::GCONF, GCONF, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let error = driver.verify_link();
+        let expected = LinkError::PatternMismatch { written: pattern(0), read: pattern(0) ^ 0x20 };
+        assert!(matches!(error, Err(Error::Link(link)) if link == expected));
+        assert_eq!(driver.shadow().gconf().0, GCONF);
+        spi.done();
+    }
+}
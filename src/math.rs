@@ -0,0 +1,35 @@
+//! Floating point functions missing from `core`.
+//!
+//! These favour small code size over precision, which is plenty for the conversions of this crate.
+
+/// Square root by Newton's method, `0` for negative input.
+pub(crate) fn sqrt(x: f32) -> f32 {
+    if x <= 0.0 {
+        return 0.0;
+    }
+    // Halving the exponent gives a first guess within a factor of two.
+    let mut y = f32::from_bits((x.to_bits() >> 1) + 0x1FC0_0000);
+    for _ in 0..4 {
+        y = 0.5 * (y + x / y);
+    }
+    y
+}
+
+/// Sine by a Taylor series on the first quarter wave, which the argument is folded onto.
+pub(crate) fn sin(x: f32) -> f32 {
+    use core::f32::consts::{FRAC_PI_2, PI, TAU};
+    let mut x = x % TAU;
+    if x < 0.0 {
+        x += TAU;
+    }
+    let (x, sign) = if x > PI { (x - PI, -1.0) } else { (x, 1.0) };
+    let x = if x > FRAC_PI_2 { PI - x } else { x };
+    let x2 = x * x;
+    let mut term = x;
+    let mut sum = x;
+    for n in 1..6 {
+        term *= -x2 / ((2 * n) * (2 * n + 1)) as f32;
+        sum += term;
+    }
+    sign * sum
+}
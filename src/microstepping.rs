@@ -0,0 +1,271 @@
+//! Changing the microstep resolution at runtime.
+//!
+//! Positions, velocities and accelerations of the ramp generator count microsteps of the
+//! resolution in `CHOPCONF::mres`, so changing it alone changes the meaning of all of them. The
+//! change is therefore made at standstill on a fullstep position, where every resolution has a
+//! microstep, and all position and ramp registers are rescaled in the same batch as `CHOPCONF`.
+//!
+//! `TPWMTHRS`, `TCOOLTHRS` and `THIGH` are compared against `TSTEP`, which is measured in 1/256
+//! microsteps regardless of the resolution, so they keep their physical meaning and are left
+//! unchanged. `VDCMIN` counts microsteps and is rescaled.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address, State};
+use crate::{Action, Error, Tmc5130};
+
+/// Velocity of the move to the next fullstep if `VMAX` is 0.
+const ALIGN_VMAX: u32 = 10_000;
+
+/// `MSCNT` of the fullstep positions, modulo one fullstep.
+const FULLSTEP_PHASE: i32 = 128;
+
+/// `MSCNT` counts per fullstep.
+const MSCNT_PER_FULLSTEP: i32 = 256;
+
+/// Scales a value from `from` to `to` microsteps per fullstep, rounding to the nearest value.
+fn rescale(value: i64, from: u32, to: u32) -> i64 {
+    if to >= from {
+        value * (to / from) as i64
+    } else {
+        let divisor = (from / to) as i64;
+        (value + divisor / 2).div_euclid(divisor)
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Changes the microstep resolution to `microsteps` per fullstep, 1 to 256 in powers of two,
+    /// keeping the physical position and ramp.
+    ///
+    /// The motor has to be at standstill. If it is not on a fullstep position according to
+    /// `MSCNT`, it is first moved to the nearest one in positioning mode. Then `XACTUAL`,
+    /// `XTARGET`, `X_COMPARE`, the ramp registers and `VDCMIN` are rescaled and written together
+    /// with `CHOPCONF` in one batch. The soft limits, backlash compensation and compare schedule
+    /// are rescaled as well. Positions that do not fall on a microstep of a coarser resolution
+    /// are rounded. `D1` and `VSTOP` are kept at 1 or above, as the ramp generator requires in
+    /// positioning mode.
+    ///
+    /// The velocity thresholds `TPWMTHRS`, `TCOOLTHRS` and `THIGH` are deliberately not rescaled:
+    /// they count 1/256 microsteps, so they already keep their physical speed, and rescaling them
+    /// would move the switching points.
+    pub fn set_microstepping<D>(&mut self, microsteps: u16, delay: &mut D, poll_interval_us: u32, timeout_ms: u32) -> Result<reg::SPISTATUS, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        if !microsteps.is_power_of_two() || microsteps > 256 {
+            return Err(Error::OutOfRange);
+        }
+        let (_, ramp_stat) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+        if !ramp_stat.vzero() {
+            return Err(Error::NotAtStandstill);
+        }
+        let (status, mut chopconf) = self.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+        let from = crate::ramp::microsteps(chopconf.mres());
+        let to = microsteps as u32;
+        if from == to {
+            return Ok(status);
+        }
+
+        let ramp = self.ramp();
+        let scale = |value: u32, max: u32| {
+            let value = rescale(value as i64, from, to);
+            if value > max as i64 {
+                Err(Error::OutOfRange)
+            } else {
+                Ok(value as u32)
+            }
+        };
+        let mut vstart = reg::VSTART::default();
+        vstart.set(scale(ramp.vstart, (1 << 18) - 1)?);
+        let mut a1 = reg::A1::default();
+        a1.set(scale(ramp.a1 as u32, u16::MAX as u32)? as u16);
+        let mut v1 = reg::V1::default();
+        v1.set(scale(ramp.v1, (1 << 20) - 1)?);
+        let mut amax = reg::AMAX::default();
+        amax.set(scale(ramp.amax as u32, u16::MAX as u32)? as u16);
+        let mut vmax = reg::VMAX::default();
+        vmax.set(scale(ramp.vmax, (1 << 23) - 512)?);
+        let mut dmax = reg::DMAX::default();
+        dmax.set(scale(ramp.dmax as u32, u16::MAX as u32)? as u16);
+        let mut d1 = reg::D1::default();
+        d1.set(scale(ramp.d1 as u32, u16::MAX as u32)?.max(1) as u16);
+        let mut vstop = reg::VSTOP::default();
+        vstop.set(scale(ramp.vstop, (1 << 18) - 1)?.max(1));
+        let mut vdcmin = reg::VDCMIN::default();
+        vdcmin.set(scale(self.shadow.vdcmin().get(), (1 << 23) - 1)?);
+        let ramp = [vstart.into(), a1.into(), v1.into(), amax.into(), vmax.into(), dmax.into(), d1.into(), vstop.into(), vdcmin.into()];
+
+        self.align_to_fullstep(from, delay, poll_interval_us, timeout_ms)?;
+
+        // Work in chip coordinates, so the backlash offset scales with the positions.
+        let backlash = self.backlash.take();
+        let overridden = self.soft_limits_overridden();
+        self.set_soft_limits_override(true);
+        chopconf.set_mres(8 - to.trailing_zeros());
+        let result = self.set_microstepping_batch(from, to, chopconf, ramp);
+        self.set_soft_limits_override(overridden);
+        self.backlash = backlash;
+        let status = result?;
+
+        let position = |value: i32| rescale(value as i64, from, to).clamp(i32::MIN as i64, i32::MAX as i64) as i32;
+        if let Some(backlash) = self.backlash.as_mut() {
+            backlash.distance = rescale(backlash.distance as i64, from, to) as u32;
+            backlash.offset = position(backlash.offset);
+        }
+        if let Some(limits) = self.soft_limits.as_mut() {
+            limits.min = position(limits.min);
+            limits.max = position(limits.max);
+        }
+        if let Some(schedule) = self.compare_schedule.as_mut() {
+            schedule.next = position(schedule.next);
+            schedule.spacing = position(schedule.spacing);
+        }
+        Ok(status)
+    }
+
+    /// Moves the motor to the nearest fullstep position, if it is not on one already.
+    ///
+    /// The target is written in chip coordinates, so neither the soft limits nor the backlash
+    /// compensation move it off the fullstep.
+    fn align_to_fullstep<D>(&mut self, from: u32, delay: &mut D, poll_interval_us: u32, timeout_ms: u32) -> Result<(), Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let (_, mscnt) = self.read_register::<reg::MSCNT>().map_err(Error::Spi)?;
+        let phase = (FULLSTEP_PHASE - mscnt.get() as i32).rem_euclid(MSCNT_PER_FULLSTEP);
+        let phase = if phase >= MSCNT_PER_FULLSTEP / 2 { phase - MSCNT_PER_FULLSTEP } else { phase };
+        let step = MSCNT_PER_FULLSTEP / from as i32;
+        let mut distance = (phase + phase.signum() * step / 2) / step;
+        if distance == 0 {
+            return Ok(());
+        }
+        // The sine table runs backwards with an inverted motor direction.
+        if self.shadow.gconf().shaft() {
+            distance = -distance;
+        }
+
+        // The shadow keeps the position read in chip coordinates.
+        self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        let xactual = self.shadow.xactual().get();
+        let vmax = *self.shadow.vmax();
+        if vmax.get() == 0 {
+            let mut align_vmax = reg::VMAX::default();
+            align_vmax.set(ALIGN_VMAX);
+            self.write_register(align_vmax)?;
+        }
+        self.write_raw(Address::XTARGET, xactual.wrapping_add(distance) as u32).map_err(Error::Spi)?;
+        self.write_register(reg::RAMPMODE::POSITIONING)?;
+        let reached = self.poll_ramp_stat(delay, poll_interval_us, timeout_ms, |ramp_stat| ramp_stat.position_reached());
+        self.write_register(vmax)?;
+        reached?.ok_or(Error::Timeout)?;
+        Ok(())
+    }
+
+    /// Writes the rescaled positions, the ramp registers and `CHOPCONF` in one batch.
+    ///
+    /// `HOLD` keeps the ramp generator from moving while `XACTUAL` and `XTARGET` are updated one
+    /// after the other, and the ramp mode is restored at the end.
+    fn set_microstepping_batch(&mut self, from: u32, to: u32, chopconf: reg::CHOPCONF, ramp: [State; 9]) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let (_, xactual) = self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        let (_, xtarget) = self.read_register::<reg::XTARGET>().map_err(Error::Spi)?;
+        let position = |value: i32| i32::try_from(rescale(value as i64, from, to)).map_err(|_| Error::OutOfRange);
+        let mut new_xactual = reg::XACTUAL::default();
+        new_xactual.set(position(xactual.get())?);
+        let mut new_xtarget = reg::XTARGET::default();
+        new_xtarget.set(position(xtarget.get())?);
+        let x_compare = reg::X_COMPARE(position(self.shadow.x_compare().0 as i32)? as u32);
+        let rampmode = *self.shadow.rampmode();
+
+        let [vstart, a1, v1, amax, vmax, dmax, d1, vstop, vdcmin] = ramp;
+        let states: [State; 15] = [
+            reg::RAMPMODE::HOLD.into(),
+            new_xactual.into(),
+            new_xtarget.into(),
+            x_compare.into(),
+            vstart,
+            a1,
+            v1,
+            amax,
+            vmax,
+            dmax,
+            d1,
+            vstop,
+            vdcmin,
+            chopconf.into(),
+            rampmode.into(),
+        ];
+        let mut actions = states.each_ref().map(Action::write);
+        self.bulk_register_action(&mut actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+
+    use super::*;
+    use crate::backlash::Backlash;
+    use crate::mock_peripherals::{driver, read_register, write, VZERO};
+
+    #[test]
+    fn rescales_everything_in_one_batch() {
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_toff(3);
+        let mut datagrams = read_register(Address::RAMP_STAT, VZERO).to_vec();
+        datagrams.extend(read_register(Address::CHOPCONF, chopconf.0));
+        // On a fullstep already.
+        datagrams.extend(read_register(Address::MSCNT, 128));
+        datagrams.extend(read_register(Address::XACTUAL, 25_600));
+        datagrams.extend(read_register(Address::XTARGET, 25_600));
+        chopconf.set_mres(8);
+        datagrams.extend([
+            write(Address::RAMPMODE, 3, 0),
+            write(Address::XACTUAL, 100, 0),
+            write(Address::XTARGET, 100, 0),
+            write(Address::X_COMPARE, 0, 0),
+            write(Address::VSTART, 0, 0),
+            write(Address::A1, 0, 0),
+            write(Address::V1, 0, 0),
+            write(Address::AMAX, 100, 0),
+            write(Address::VMAX, 1_000, 0),
+            write(Address::DMAX, 100, 0),
+            // `D1` and `VSTOP` would round to 0.
+            write(Address::D1, 1, 0),
+            write(Address::VSTOP, 1, 0),
+            write(Address::VDCMIN, 0, 0),
+            write(Address::CHOPCONF, chopconf.0, 0),
+            write(Address::RAMPMODE, 0, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        let ramp = [
+            (Address::AMAX, 25_600),
+            (Address::VMAX, 256_000),
+            (Address::DMAX, 25_600),
+            (Address::D1, 100),
+            (Address::VSTOP, 10),
+        ];
+        for (addr, value) in ramp {
+            driver.shadow.set_state(State::from_addr_and_data(addr, value));
+        }
+        driver.set_microstepping(1, &mut NoopDelay::new(), 10, 100).unwrap();
+        spi.done();
+    }
+
+    #[test]
+    fn aligns_in_chip_coordinates() {
+        let mut datagrams = read_register(Address::MSCNT, 130).to_vec();
+        datagrams.extend(read_register(Address::XACTUAL, 1_602));
+        datagrams.extend([write(Address::XTARGET, 1_600, 0), write(Address::RAMPMODE, 0, 0)]);
+        // `position_reached`.
+        datagrams.extend(read_register(Address::RAMP_STAT, 1 << 9));
+        datagrams.push(write(Address::VMAX, 5_000, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.shadow.set_state(State::from_addr_and_data(Address::VMAX, 5_000));
+        driver.backlash = Some(Backlash { distance: 10, offset: -10, direction: Some(crate::Direction::Positive) });
+        driver.align_to_fullstep(256, &mut NoopDelay::new(), 10, 100).unwrap();
+        assert_eq!(driver.backlash().unwrap().offset, -10);
+        spi.done();
+    }
+}
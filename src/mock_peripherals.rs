@@ -1,65 +1,46 @@
-use mockall::*;
+//! SPI mocks for the driver tests.
+//!
+//! Each datagram is one `SpiDevice` transaction of an address byte, answered by the status, and
+//! four data bytes, answered by the data of the previous read request.
 
-#[derive(Debug, PartialEq)]
-pub enum Error {}
-impl embedded_hal::spi::Error for Error {
-    fn kind(&self) -> embedded_hal::spi::ErrorKind {
-        todo!()
-    }
-}
+use embedded_hal_mock::eh1::spi::{Mock, Transaction};
 
-mock! {
-    pub SpiBus {} // Name of the mock struct, less the "Mock" prefix
-    impl embedded_hal::spi::SpiBus for SpiBus { // specification of the trait to mock
-fn flush(&mut self) -> Result<(), <Self as embedded_hal::spi::ErrorType>::Error> { todo!() }
-fn transfer_in_place(&mut self, a: &mut [u8]) -> Result<(), <Self as embedded_hal::spi::ErrorType>::Error> { todo!() }
-fn transfer(&mut self, a: &mut [u8], b: &[u8]) -> Result<(), <Self as embedded_hal::spi::ErrorType>::Error> { todo!() }
-fn write(&mut self, a: &[u8]) -> Result<(), <Self as embedded_hal::spi::ErrorType>::Error> { todo!() }
-fn read(&mut self, a: &mut [u8]) -> Result<(), <Self as embedded_hal::spi::ErrorType>::Error> { todo!() }
-    }
-    impl embedded_hal::spi::ErrorType for SpiBus {
-    type Error = Error;
-}
+use crate::reg::Address;
+use crate::Tmc5130;
+
+/// `RAMP_STAT` with `vzero` set, as after a stop.
+pub const VZERO: u32 = 1 << 10;
+
+/// The expected transactions of one datagram.
+pub type Datagram = [Transaction<u8>; 4];
+
+/// A datagram sending `address` and `data`, answered with `status` and `reply`.
+pub fn datagram(address: u8, data: u32, status: u8, reply: u32) -> Datagram {
+    [
+        Transaction::transaction_start(),
+        Transaction::transfer_in_place(vec![address], vec![status]),
+        Transaction::transfer_in_place(data.to_be_bytes().to_vec(), reply.to_be_bytes().to_vec()),
+        Transaction::transaction_end(),
+    ]
 }
-mock! {
-pub SimpleHalSpiDevice {
+
+/// A write of `data` to `addr`, answered with `reply`.
+pub fn write(addr: Address, data: u32, reply: u32) -> Datagram {
+    datagram(addr as u8 | 0x80, data, 0, reply)
 }
 
-impl embedded_hal::spi::SpiDevice<u8> for SimpleHalSpiDevice {
-    fn transaction<'a>(
-        &mut self,
-        operations: &mut [embedded_hal::spi::Operation<'a, u8>],
-    ) -> Result<(), Error> {
-        for op in operations {
-            match op {
-                embedded_hal::spi::Operation::Read(read) => {
-                    self.bus.read(read).unwrap();
-                }
-                embedded_hal::spi::Operation::Write(write) => {
-                    self.bus.write(write).unwrap();
-                }
-                embedded_hal::spi::Operation::Transfer(read, write) => {
-                    self.bus.transfer(read, write).unwrap();
-                }
-                embedded_hal::spi::Operation::TransferInPlace(words) => {
-                    self.bus.transfer_in_place(words).unwrap();
-                }
-                embedded_hal::spi::Operation::DelayNs(us) => {
-                    //embedded_hal::delay::DelayNs::delay_us(&mut Delay::new(), *us);
-                }
-            }
-        }
-        Ok(())
-    }
+/// A read request for `addr`, answered with `reply`.
+pub fn read(addr: Address, reply: u32) -> Datagram {
+    datagram(addr as u8, 0, 0, reply)
 }
 
-impl embedded_hal::spi::ErrorType for SimpleHalSpiDevice {
-    type Error = Error;
+/// The two datagrams of a `read_register` of `addr` returning `value`, after an empty reply.
+pub fn read_register(addr: Address, value: u32) -> [Datagram; 2] {
+    [read(addr, 0), read(addr, value)]
 }
-    }
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum MockOperation<'a, Word: 'static> {
-    Read(&'a [Word]),
-    Write(&'a [Word]),
+/// A driver on a mock expecting `datagrams`, and a handle to the mock to call `done` on.
+pub fn driver(datagrams: &[Datagram]) -> (Tmc5130<Mock<u8>>, Mock<u8>) {
+    let spi = Mock::new(datagrams.iter().flatten());
+    (Tmc5130::new(spi.clone()), spi)
 }
@@ -0,0 +1,317 @@
+//! Generator for the microstep lookup table, `MSLUT0..7`, `MSLUTSEL` and `MSLUTSTART`.
+//!
+//! The chip derives the coil currents from a quarter sine wave of 256 entries. The table is
+//! delta encoded: each entry holds one bit in `MSLUT0..7`, and the step to the next entry is the
+//! bit plus the base of its segment. `MSLUTSEL` splits the quarter wave into up to four segments
+//! at `X1`, `X2` and `X3`, each with a width `W0..W3` selecting the steps `-1/0`, `0/+1`,
+//! `+1/+2` or `+2/+3`. `MSLUTSTART` holds the first entry of the sine and of the cosine wave.
+//!
+//! [`MicrostepTable`] finds the fewest segments for a quarter wave given as samples or as a
+//! function, and checks that the encoding decodes back to the same wave.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::math::sin;
+use crate::reg::{self, State};
+use crate::{Action, Error, Tmc5130};
+
+/// Number of entries of the quarter wave table.
+pub const QUARTER_WAVE_ENTRIES: usize = 256;
+
+/// Number of samples describing a quarter wave: the table entries followed by the first entry of
+/// the next quarter, the peak of the wave.
+pub const QUARTER_WAVE_SAMPLES: usize = QUARTER_WAVE_ENTRIES + 1;
+
+/// The peak of the power-on table.
+pub const DEFAULT_AMPLITUDE: u8 = 248;
+
+/// Reasons a quarter wave can not be encoded.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum MslutError {
+    /// The step from `entry` to the next entry lies outside of -1 to +3.
+    StepOutOfRange { entry: u8 },
+    /// The steps need more than four segments of neighbouring values.
+    TooManySegments,
+    /// The encoding does not decode back to the samples.
+    RoundTrip,
+    /// The wave shape has no positive peak to scale to the amplitude.
+    InvalidAmplitude,
+}
+
+/// Predefined wave shapes for [`MicrostepTable::preset`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Waveform {
+    /// A pure sine wave, like the power-on table.
+    Sine,
+    /// A sine wave with a share of the third harmonic added. Positive shares flatten the top of
+    /// the wave, negative shares sharpen it.
+    ThirdHarmonic { share: f32 },
+    /// A sine wave blended with a triangle wave by the given share, 0 to 1, raising the current
+    /// between the fullstep positions.
+    TriangleBlend { share: f32 },
+}
+
+/// An encoded microstep table, ready to be written.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MicrostepTable {
+    mslut: [u32; 8],
+    mslutsel: reg::MSLUTSEL,
+    mslutstart: reg::MSLUTSTART,
+}
+
+impl MicrostepTable {
+    /// The table the chip uses after power-on.
+    pub fn power_on() -> Self {
+        Self {
+            mslut: [0xAAAA_B554, 0x4A95_54AA, 0x2449_2929, 0x1010_4222, 0xFBFF_FFFF, 0xB5BB_777D, 0x4929_5556, 0x0040_4222],
+            mslutsel: reg::MSLUTSEL::from(0xFFFF_8056),
+            mslutstart: reg::MSLUTSTART::from(0x00F7_0000),
+        }
+    }
+
+    /// Encodes a quarter wave given as samples.
+    ///
+    /// The cosine wave starts at the last sample. Fails if two neighbouring samples differ by
+    /// more than the table can step, or if the steps do not fit into four segments.
+    pub fn from_samples(samples: &[u8; QUARTER_WAVE_SAMPLES]) -> Result<Self, MslutError> {
+        let mut steps = [0i16; QUARTER_WAVE_ENTRIES];
+        for (entry, step) in steps.iter_mut().enumerate() {
+            *step = samples[entry + 1] as i16 - samples[entry] as i16;
+            if !(-1..=3).contains(step) {
+                return Err(MslutError::StepOutOfRange { entry: entry as u8 });
+            }
+        }
+
+        // Extending each segment as far as possible gives the fewest segments.
+        let mut widths = [0u16; 4];
+        let mut starts = [0u16; 3];
+        let mut segment = 0;
+        let (mut low, mut high) = (steps[0], steps[0]);
+        for (entry, &step) in steps.iter().enumerate().skip(1) {
+            if high.max(step) - low.min(step) > 1 {
+                widths[segment] = segment_width(low, high);
+                segment += 1;
+                if segment == widths.len() {
+                    return Err(MslutError::TooManySegments);
+                }
+                starts[segment - 1] = entry as u16;
+                (low, high) = (step, step);
+            } else {
+                (low, high) = (low.min(step), high.max(step));
+            }
+        }
+        widths[segment] = segment_width(low, high);
+        // The chip requires 0 < X1 < X2 < X3, so unused boundaries split segments from the end of
+        // the table, repeating the width of the segment they split.
+        let mut candidate = QUARTER_WAVE_ENTRIES as u16 - 1;
+        while segment < starts.len() {
+            while starts[..segment].contains(&candidate) {
+                candidate -= 1;
+            }
+            let split = starts[..segment].partition_point(|&start| start < candidate);
+            starts.copy_within(split..segment, split + 1);
+            starts[split] = candidate;
+            widths.copy_within(split..=segment, split + 1);
+            segment += 1;
+        }
+
+        let mut mslutsel = reg::MSLUTSEL::default();
+        mslutsel.set_w0(widths[0]);
+        mslutsel.set_w1(widths[1]);
+        mslutsel.set_w2(widths[2]);
+        mslutsel.set_w3(widths[3]);
+        mslutsel.set_x1(starts[0]);
+        mslutsel.set_x2(starts[1]);
+        mslutsel.set_x3(starts[2]);
+        let mut mslutstart = reg::MSLUTSTART::default();
+        mslutstart.set_start_sin(samples[0] as u32);
+        mslutstart.set_start_sin90(samples[QUARTER_WAVE_ENTRIES] as u32);
+
+        let mut table = Self { mslut: [0; 8], mslutsel, mslutstart };
+        for (entry, &step) in steps.iter().enumerate() {
+            let bit = (step - table.step_base(entry)) as u32;
+            table.mslut[entry / 32] |= bit << (entry % 32);
+        }
+
+        let decoded = table.samples();
+        if decoded.iter().zip(samples.iter()).any(|(&decoded, &sample)| decoded != sample as i16) {
+            return Err(MslutError::RoundTrip);
+        }
+        Ok(table)
+    }
+
+    /// Encodes a quarter wave given as a function.
+    ///
+    /// `wave` maps the phase within the quarter wave, 0 to 1, to the current relative to
+    /// `amplitude`, 0 to 1. Values outside of that range are clamped.
+    pub fn from_fn<F>(amplitude: u8, wave: F) -> Result<Self, MslutError>
+    where F: Fn(f32) -> f32
+    {
+        let mut samples = [0u8; QUARTER_WAVE_SAMPLES];
+        for (i, sample) in samples.iter_mut().enumerate() {
+            let value = wave(i as f32 / QUARTER_WAVE_ENTRIES as f32).clamp(0.0, 1.0);
+            *sample = (value * amplitude as f32 + 0.5) as u8;
+        }
+        Self::from_samples(&samples)
+    }
+
+    /// Encodes one of the predefined wave shapes, scaled so its peak reaches `amplitude`.
+    pub fn preset(waveform: Waveform, amplitude: u8) -> Result<Self, MslutError> {
+        use core::f32::consts::FRAC_PI_2;
+        let shape = |phase: f32| {
+            let sine = sin(phase * FRAC_PI_2);
+            match waveform {
+                Waveform::Sine => sine,
+                Waveform::ThirdHarmonic { share } => sine + share * sin(3.0 * phase * FRAC_PI_2),
+                Waveform::TriangleBlend { share } => (1.0 - share) * sine + share * phase,
+            }
+        };
+        let peak = (0..QUARTER_WAVE_SAMPLES)
+            .map(|i| shape(i as f32 / QUARTER_WAVE_ENTRIES as f32))
+            .fold(0.0, f32::max);
+        if peak <= 0.0 {
+            return Err(MslutError::InvalidAmplitude);
+        }
+        Self::from_fn(amplitude, |phase| shape(phase) / peak)
+    }
+
+    /// Decodes the table into its quarter wave, the inverse of [`MicrostepTable::from_samples`].
+    ///
+    /// The last sample continues the steps past the final entry, which need not equal the start of
+    /// the cosine wave for tables not generated here.
+    pub fn samples(&self) -> [i16; QUARTER_WAVE_SAMPLES] {
+        let mut samples = [0i16; QUARTER_WAVE_SAMPLES];
+        samples[0] = self.mslutstart.start_sin() as i16;
+        for entry in 0..QUARTER_WAVE_ENTRIES {
+            let bit = (self.mslut[entry / 32] >> (entry % 32)) & 1;
+            samples[entry + 1] = samples[entry] + self.step_base(entry) + bit as i16;
+        }
+        samples
+    }
+
+    /// The raw `MSLUT0..7` values.
+    pub fn mslut(&self) -> [u32; 8] {
+        self.mslut
+    }
+
+    /// The segment widths and boundaries.
+    pub fn mslutsel(&self) -> reg::MSLUTSEL {
+        self.mslutsel
+    }
+
+    /// The start values of the sine and cosine waves.
+    pub fn mslutstart(&self) -> reg::MSLUTSTART {
+        self.mslutstart
+    }
+
+    /// The step of `entry` for a cleared bit.
+    fn step_base(&self, entry: usize) -> i16 {
+        let entry = entry as u16;
+        let width = if entry < self.mslutsel.x1() {
+            self.mslutsel.w0()
+        } else if entry < self.mslutsel.x2() {
+            self.mslutsel.w1()
+        } else if entry < self.mslutsel.x3() {
+            self.mslutsel.w2()
+        } else {
+            self.mslutsel.w3()
+        };
+        width as i16 - 1
+    }
+}
+
+impl Default for MicrostepTable {
+    fn default() -> Self {
+        Self::power_on()
+    }
+}
+
+/// The width `W` whose steps cover `low` to `high`, which differ by at most one.
+fn segment_width(low: i16, high: i16) -> u16 {
+    if high > low || high == 3 {
+        high as u16
+    } else {
+        (high + 1) as u16
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Writes a microstep table in one batch.
+    ///
+    /// The chip only reads the table while stepping, so it takes effect with the next microstep.
+    pub fn set_microstep_table(&mut self, table: &MicrostepTable) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let [m0, m1, m2, m3, m4, m5, m6, m7] = table.mslut;
+        let states: [State; 10] = [
+            reg::MSLUT0(m0).into(),
+            reg::MSLUT1(m1).into(),
+            reg::MSLUT2(m2).into(),
+            reg::MSLUT3(m3).into(),
+            reg::MSLUT4(m4).into(),
+            reg::MSLUT5(m5).into(),
+            reg::MSLUT6(m6).into(),
+            reg::MSLUT7(m7).into(),
+            table.mslutsel.into(),
+            table.mslutstart.into(),
+        ];
+        let mut actions = states.each_ref().map(Action::write);
+        self.bulk_register_action(&mut actions)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sine_round_trips() {
+        let table = MicrostepTable::preset(Waveform::Sine, DEFAULT_AMPLITUDE).unwrap();
+        let samples = table.samples();
+        assert_eq!(samples[0], 0);
+        assert_eq!(samples[QUARTER_WAVE_ENTRIES], DEFAULT_AMPLITUDE as i16);
+        assert!(samples.windows(2).all(|pair| pair[1] >= pair[0]));
+    }
+
+    #[test]
+    fn power_on_table_reencodes() {
+        let samples = MicrostepTable::power_on().samples().map(|sample| sample as u8);
+        let table = MicrostepTable::from_samples(&samples).unwrap();
+        assert_eq!(table.samples(), MicrostepTable::power_on().samples());
+    }
+
+    #[test]
+    fn distortion_presets_encode() {
+        assert!(MicrostepTable::preset(Waveform::ThirdHarmonic { share: 0.1 }, DEFAULT_AMPLITUDE).is_ok());
+        assert!(MicrostepTable::preset(Waveform::TriangleBlend { share: 0.3 }, DEFAULT_AMPLITUDE).is_ok());
+    }
+
+    #[test]
+    fn unused_boundaries_increase() {
+        // Constant steps need a single segment, a late change of step two.
+        let linear: [u8; QUARTER_WAVE_SAMPLES] = core::array::from_fn(|i| (i / 2) as u8);
+        let mut late = linear;
+        late[QUARTER_WAVE_ENTRIES] = late[QUARTER_WAVE_ENTRIES - 1] + 3;
+        for samples in [linear, late] {
+            let table = MicrostepTable::from_samples(&samples).unwrap();
+            let mslutsel = table.mslutsel();
+            assert!(0 < mslutsel.x1() && mslutsel.x1() < mslutsel.x2() && mslutsel.x2() < mslutsel.x3());
+        }
+    }
+
+    #[test]
+    fn rejects_shape_without_peak() {
+        let table = MicrostepTable::preset(Waveform::TriangleBlend { share: f32::NAN }, DEFAULT_AMPLITUDE);
+        assert_eq!(table, Err(MslutError::InvalidAmplitude));
+    }
+
+    #[test]
+    fn rejects_large_steps() {
+        let mut samples = [0u8; QUARTER_WAVE_SAMPLES];
+        samples[10] = 10;
+        assert_eq!(MicrostepTable::from_samples(&samples), Err(MslutError::StepOutOfRange { entry: 9 }));
+    }
+}
@@ -0,0 +1,226 @@
+//! Touch probing using the reference switch position latch.
+//!
+//! The chip copies `XACTUAL` into `XLATCH` on a configurable edge of the REFL or REFR input, which
+//! records the exact position at which a probe switches, independent of how fast the host polls.
+//! Unlike homing, probing does not change the coordinate system, and the reference switch is not
+//! enabled as a stop switch.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Direction, Error, Tmc5130};
+
+/// The reference switch input the probe is connected to.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum ReferenceSwitch {
+    /// The REFL input.
+    Left,
+    /// The REFR input.
+    Right,
+}
+
+/// The switch transition that latches the position.
+///
+/// Active and inactive follow the polarity configured in `SW_MODE::pol_stop_l` and `pol_stop_r`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum SwitchEdge {
+    /// The switch becomes active.
+    Active,
+    /// The switch becomes inactive.
+    Inactive,
+}
+
+/// Configuration of a probing move.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ProbeConfig {
+    /// The input the probe is connected to.
+    pub switch: ReferenceSwitch,
+    /// The transition of the input that is recorded.
+    pub edge: SwitchEdge,
+    /// Stops the motor once the probe triggered, otherwise the move continues to its end.
+    pub stop_on_trigger: bool,
+    /// Time allowed for the whole move, in milliseconds.
+    pub timeout_ms: u32,
+    /// Delay between two polls of `RAMP_STAT`, in microseconds.
+    pub poll_interval_us: u32,
+}
+
+impl Default for ProbeConfig {
+    fn default() -> Self {
+        Self {
+            switch: ReferenceSwitch::Left,
+            edge: SwitchEdge::Active,
+            stop_on_trigger: true,
+            timeout_ms: 10_000,
+            poll_interval_us: 1_000,
+        }
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Moves up to `max_distance` microsteps in `direction` and records the position at which the
+    /// probe input switches.
+    ///
+    /// The move is a positioning move with the configured ramp, so it is subject to the soft
+    /// limits. Returns the latched position, or `None` if the end of the move was reached without
+    /// the probe switching. `SW_MODE` is restored afterwards. If neither happens in time, the motor
+    /// is stopped before [`Error::Timeout`] is returned.
+    ///
+    /// Fails with [`Error::OutOfRange`] if `max_distance` exceeds `i32::MAX`.
+    pub fn probe<D>(&mut self, direction: Direction, max_distance: u32, config: &ProbeConfig, delay: &mut D) -> Result<Option<i32>, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let max_distance = i32::try_from(max_distance).map_err(|_| Error::OutOfRange)?;
+        let (_, sw_mode) = self.read_register::<reg::SW_MODE>().map_err(Error::Spi)?;
+        let result = self.probe_inner(direction, max_distance, config, sw_mode, delay);
+        self.write_register(sw_mode)?;
+        result
+    }
+
+    fn probe_inner<D>(&mut self, direction: Direction, max_distance: i32, config: &ProbeConfig, sw_mode: reg::SW_MODE, delay: &mut D) -> Result<Option<i32>, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let mut probe_sw_mode = sw_mode;
+        let active = config.edge == SwitchEdge::Active;
+        match config.switch {
+            ReferenceSwitch::Left => {
+                probe_sw_mode.set_stop_l_enable(false);
+                probe_sw_mode.set_latch_l_active(active);
+                probe_sw_mode.set_latch_l_inactive(!active);
+            }
+            ReferenceSwitch::Right => {
+                probe_sw_mode.set_stop_r_enable(false);
+                probe_sw_mode.set_latch_r_active(active);
+                probe_sw_mode.set_latch_r_inactive(!active);
+            }
+        }
+        self.write_register(probe_sw_mode)?;
+
+        // The latch flags are cleared by writing them as 1.
+        let mut clear_latch = reg::RAMP_STAT::default();
+        clear_latch.set_status_latch_l(true);
+        clear_latch.set_status_latch_r(true);
+        self.write_register(clear_latch)?;
+
+        let (_, xactual) = self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        let mut xtarget = reg::XTARGET::default();
+        xtarget.set(xactual.get().wrapping_add(direction.signum() * max_distance));
+        self.write_register(reg::RAMPMODE::POSITIONING)?;
+        self.write_register(xtarget)?;
+
+        let latched = |ramp_stat: reg::RAMP_STAT| match config.switch {
+            ReferenceSwitch::Left => ramp_stat.status_latch_l(),
+            ReferenceSwitch::Right => ramp_stat.status_latch_r(),
+        };
+        let ramp_stat = self.poll_ramp_stat(delay, config.poll_interval_us, config.timeout_ms, |ramp_stat| {
+            latched(ramp_stat) || ramp_stat.position_reached()
+        })?;
+        let ramp_stat = match ramp_stat {
+            Some(ramp_stat) => ramp_stat,
+            None => {
+                // Do not leave the motor running towards the end of the move.
+                self.stop_positioning(delay, config.poll_interval_us, config.timeout_ms)?;
+                return Err(Error::Timeout);
+            }
+        };
+        if !latched(ramp_stat) {
+            return Ok(None);
+        }
+
+        let (_, xlatch) = self.read_register::<reg::XLATCH>().map_err(Error::Spi)?;
+        if config.stop_on_trigger {
+            self.stop_positioning(delay, config.poll_interval_us, config.timeout_ms)?;
+        }
+        Ok(Some(xlatch.0 as i32))
+    }
+
+    /// Stops a positioning move with the deceleration ramp and holds the motor where it stopped.
+    ///
+    /// Setting `VMAX` to zero makes the ramp generator decelerate, after which `XTARGET` is moved
+    /// to the stop position and `VMAX` restored.
+    pub fn stop_positioning<D>(&mut self, delay: &mut D, poll_interval_us: u32, timeout_ms: u32) -> Result<(), Error<SPI::Error>>
+    where D: DelayNs
+    {
+        self.stop_with_ramp(delay, poll_interval_us, timeout_ms, false)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write, Datagram, VZERO};
+    use crate::reg::Address;
+
+    fn start_move(xactual: u32, xtarget: u32) -> Vec<Datagram> {
+        let mut sw_mode = reg::SW_MODE::default();
+        sw_mode.set_latch_l_active(true);
+        let mut datagrams = read_register(Address::SW_MODE, 0).to_vec();
+        datagrams.extend([write(Address::SW_MODE, sw_mode.0, 0), write(Address::RAMP_STAT, 0b1100, 0)]);
+        datagrams.extend(read_register(Address::XACTUAL, xactual));
+        datagrams.extend([write(Address::RAMPMODE, 0, 0), write(Address::XTARGET, xtarget, 0)]);
+        datagrams
+    }
+
+    fn stop(xactual: u32) -> Vec<Datagram> {
+        let mut datagrams = vec![write(Address::VMAX, 0, 0)];
+        datagrams.extend(read_register(Address::RAMP_STAT, VZERO));
+        datagrams.extend(read_register(Address::XACTUAL, xactual));
+        datagrams.extend([write(Address::XTARGET, xactual, 0), write(Address::VMAX, 0, 0)]);
+        datagrams
+    }
+
+    #[test]
+    fn returns_latched_position_and_stops() {
+        let mut datagrams = start_move(100, (100 - 500i32) as u32);
+        // `status_latch_l`.
+        datagrams.extend(read_register(Address::RAMP_STAT, 0b100));
+        datagrams.extend(read_register(Address::XLATCH, (-250i32) as u32));
+        datagrams.extend(stop((-270i32) as u32));
+        datagrams.push(write(Address::SW_MODE, 0, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let position = driver.probe(Direction::Negative, 500, &ProbeConfig::default(), &mut NoopDelay::new());
+        assert_eq!(position.unwrap(), Some(-250));
+        spi.done();
+    }
+
+    #[test]
+    fn end_of_move_without_trigger() {
+        let mut datagrams = start_move(0, 500);
+        // `position_reached`.
+        datagrams.extend(read_register(Address::RAMP_STAT, 1 << 9));
+        datagrams.push(write(Address::SW_MODE, 0, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let position = driver.probe(Direction::Positive, 500, &ProbeConfig::default(), &mut NoopDelay::new());
+        assert_eq!(position.unwrap(), None);
+        spi.done();
+    }
+
+    #[test]
+    fn timeout_stops_the_motor() {
+        let mut datagrams = start_move(0, 500);
+        datagrams.extend(read_register(Address::RAMP_STAT, 0));
+        datagrams.extend(stop(200));
+        datagrams.push(write(Address::SW_MODE, 0, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        let config = ProbeConfig { timeout_ms: 0, ..ProbeConfig::default() };
+        let result = driver.probe(Direction::Positive, 500, &config, &mut NoopDelay::new());
+        assert!(matches!(result, Err(Error::Timeout)));
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_distances_beyond_i32() {
+        let (mut driver, mut spi) = driver(&[]);
+        let result = driver.probe(Direction::Negative, 1 << 31, &ProbeConfig::default(), &mut NoopDelay::new());
+        assert!(matches!(result, Err(Error::OutOfRange)));
+        spi.done();
+    }
+}
@@ -0,0 +1,249 @@
+//! Recommended chip configurations derived from motor data.
+//!
+//! [`MotorProfile`] holds the data sheet values of a motor, and [`MotorProfile::recommend`] derives
+//! a complete, conservative configuration from them for a given supply voltage:
+//!
+//! * The run current is 85% of the rated current, the hold current half of that.
+//! * SpreadCycle uses an off time of about 8µs, with a hysteresis covering the current ripple during
+//!   the blank time and the slow decay phase.
+//! * StealthChop with automatic scaling is used up to the velocity where inductance and back EMF
+//!   take up half the supply voltage, and CoolStep and the stall output above it.
+//! * `VMAX` is half the velocity where the supply can no longer drive the rated current, reached
+//!   within half a second.
+//!
+//! The back EMF constant is estimated from the holding torque and the rated current, so the results
+//! are starting points to be refined with the tuning procedures, not optimal settings.
+
+use core::f32::consts::{PI, SQRT_2};
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::chopper::{ChopperConfig, ChopperError};
+use crate::current::CurrentSense;
+use crate::ramp::{acceleration_from_hz_per_s, velocity_from_hz, RampConfig};
+use crate::standstill::StandstillConfig;
+use crate::thresholds::{Speed, VelocityThresholds};
+use crate::{reg, Error, Tmc5130};
+
+/// Share of the rated current used as run current.
+const RUN_CURRENT_SHARE: f32 = 0.85;
+/// Share of the run current used as hold current.
+const HOLD_CURRENT_SHARE: f32 = 0.5;
+/// The targeted chopper off time, in seconds.
+const OFF_TIME_S: f32 = 8e-6;
+/// Blank time setting `TBL`, 36 clock cycles.
+const BLANK_TIME: u8 = 2;
+/// Time to accelerate to `VMAX`, in seconds.
+const RAMP_TIME_S: f32 = 0.5;
+/// The microstep resolution, interpolated to 256 microsteps by the chip.
+const MICROSTEPS: u16 = 16;
+/// `PWMCONF` after power-on.
+const PWMCONF_RESET: u32 = 0x0005_0480;
+
+/// Data sheet values of a two phase stepper motor.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct MotorProfile {
+    /// Rated RMS current per phase, in amperes.
+    pub rated_current_a: f32,
+    /// Phase resistance, in ohms.
+    pub resistance_ohms: f32,
+    /// Phase inductance, in millihenries.
+    pub inductance_mh: f32,
+    /// Full steps per revolution.
+    pub full_steps_per_rev: u16,
+    /// Holding torque at the rated current, in newton metres.
+    pub holding_torque_nm: f32,
+}
+
+impl MotorProfile {
+    /// A typical 1.8° NEMA17 motor of 40mm length.
+    pub const NEMA17_40MM: Self = Self {
+        rated_current_a: 1.7,
+        resistance_ohms: 1.5,
+        inductance_mh: 2.8,
+        full_steps_per_rev: 200,
+        holding_torque_nm: 0.42,
+    };
+
+    /// A typical 1.8° NEMA17 motor of 48mm length.
+    pub const NEMA17_48MM: Self = Self {
+        rated_current_a: 2.0,
+        resistance_ohms: 1.4,
+        inductance_mh: 3.0,
+        full_steps_per_rev: 200,
+        holding_torque_nm: 0.59,
+    };
+
+    /// A typical 1.8° NEMA23 motor of 56mm length.
+    pub const NEMA23_56MM: Self = Self {
+        rated_current_a: 2.8,
+        resistance_ohms: 0.9,
+        inductance_mh: 2.5,
+        full_steps_per_rev: 200,
+        holding_torque_nm: 1.26,
+    };
+
+    /// A typical 1.8° NEMA23 motor of 76mm length.
+    pub const NEMA23_76MM: Self = Self {
+        rated_current_a: 2.8,
+        resistance_ohms: 1.13,
+        inductance_mh: 3.6,
+        full_steps_per_rev: 200,
+        holding_torque_nm: 1.89,
+    };
+
+    /// Derives a recommended configuration for the given supply voltage and clock frequency.
+    ///
+    /// Fails only if the motor data leads to a chopper configuration outside of the datasheet
+    /// rules.
+    pub fn recommend(&self, supply_volts: f32, clock_hz: u32) -> Result<MotorConfig, ChopperError> {
+        let run_current_rms = self.rated_current_a * RUN_CURRENT_SHARE;
+        let peak_current = run_current_rms * SQRT_2;
+        let inductance = self.inductance_mh * 1e-3;
+        let clock = clock_hz as f32;
+
+        let toff = ((OFF_TIME_S * clock - 24.0) / 32.0 + 0.5).clamp(2.0, 15.0) as u8;
+        let off_time_s = (24.0 + 32.0 * toff as f32) / clock;
+        let blank_time_s = 36.0 / clock;
+        // The hysteresis covers the ripple of the blank time and the slow decay phase, in units of
+        // 1/248 of the peak current.
+        let ripple = supply_volts * blank_time_s / inductance + self.resistance_ohms * peak_current * 2.0 * off_time_s / inductance;
+        let hysteresis = ((ripple / peak_current * 248.0 + 0.5) as i8).clamp(1, 16);
+        let hysteresis_start = hysteresis.min(8);
+        let chopper = ChopperConfig::spread_cycle(hysteresis_start as u8, (hysteresis - hysteresis_start).clamp(-3, 12))
+            .off_time(toff)
+            .blank_time(BLANK_TIME)
+            .build()?;
+
+        let mut pwmconf = reg::PWMCONF::from(PWMCONF_RESET);
+        pwmconf.set_pwm_autoscale(true);
+        pwmconf.set_pwm_ampl(u8::MAX);
+
+        let stealthchop_hz = self.velocity_at_voltage(0.5 * supply_volts, peak_current);
+        let max_hz = 0.5 * self.velocity_at_voltage(supply_volts, peak_current);
+        let stealthchop = stealthchop_hz > 0.0;
+        let thresholds = VelocityThresholds {
+            stealthchop_max: stealthchop.then_some(Speed::FullStepsPerSecond(stealthchop_hz)),
+            coolstep_min: stealthchop.then_some(Speed::FullStepsPerSecond(stealthchop_hz)),
+            high_velocity: None,
+        };
+
+        let vmax_hz = max_hz * MICROSTEPS as f32;
+        let amax = acceleration_from_hz_per_s(vmax_hz / RAMP_TIME_S, clock_hz).clamp(1, u16::MAX as u32) as u16;
+        let ramp = RampConfig {
+            vstart: 0,
+            a1: amax.saturating_mul(2),
+            v1: velocity_from_hz(vmax_hz / 2.0, clock_hz),
+            amax,
+            vmax: velocity_from_hz(vmax_hz, clock_hz).min((1 << 23) - 512),
+            dmax: amax,
+            d1: amax.saturating_mul(2),
+            vstop: 10,
+        };
+
+        Ok(MotorConfig {
+            run_current_rms,
+            hold_current_rms: run_current_rms * HOLD_CURRENT_SHARE,
+            microsteps: MICROSTEPS,
+            interpolation: true,
+            chopper,
+            stealthchop,
+            pwmconf,
+            thresholds,
+            ramp,
+            standstill: StandstillConfig::default(),
+        })
+    }
+
+    /// The velocity in full steps per second at which the coil takes up `volts` to drive
+    /// `peak_current` against its resistance, inductance and back EMF, 0 if it never does.
+    fn velocity_at_voltage(&self, volts: f32, peak_current: f32) -> f32 {
+        // The back EMF constant in volts per radian per second equals the torque constant.
+        let back_emf = self.holding_torque_nm / (self.rated_current_a * SQRT_2);
+        // One electrical period takes four full steps.
+        let reactance = self.inductance_mh * 1e-3 * peak_current * self.full_steps_per_rev as f32 / 4.0;
+        let omega = (volts - self.resistance_ohms * peak_current) / (back_emf + reactance);
+        (omega / (2.0 * PI) * self.full_steps_per_rev as f32).max(0.0)
+    }
+}
+
+/// A complete chip configuration, as derived by [`MotorProfile::recommend`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MotorConfig {
+    /// Run current in RMS amperes.
+    pub run_current_rms: f32,
+    /// Hold current in RMS amperes.
+    pub hold_current_rms: f32,
+    /// Microsteps per full step, `CHOPCONF::mres`.
+    pub microsteps: u16,
+    /// Interpolation to 256 microsteps, `CHOPCONF::intpol`.
+    pub interpolation: bool,
+    /// The SpreadCycle chopper settings.
+    pub chopper: ChopperConfig,
+    /// Enables StealthChop below `thresholds.stealthchop_max`, `GCONF::en_pwm_mode`.
+    pub stealthchop: bool,
+    /// The StealthChop settings.
+    pub pwmconf: reg::PWMCONF,
+    /// The mode switching velocities.
+    pub thresholds: VelocityThresholds,
+    /// The ramp limits.
+    pub ramp: RampConfig,
+    /// The current reduction at standstill.
+    pub standstill: StandstillConfig,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Writes a complete motor configuration.
+    ///
+    /// Meant for setup at standstill, as the microstep resolution is written directly, without
+    /// rescaling positions. The motor starts moving only if the ramp generator has a target.
+    pub fn apply_motor_config(&mut self, config: &MotorConfig, sense: &CurrentSense) -> Result<(), Error<SPI::Error>> {
+        if !config.microsteps.is_power_of_two() || config.microsteps > 256 {
+            return Err(Error::OutOfRange);
+        }
+        let (_, chopconf) = self.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+        let mut chopconf = config.chopper.apply(chopconf);
+        chopconf.set_mres(8 - config.microsteps.trailing_zeros());
+        chopconf.set_intpol(config.interpolation);
+        self.write_register(chopconf)?;
+        self.set_run_current_rms(config.run_current_rms, sense)?;
+        self.set_hold_current_rms(config.hold_current_rms, sense)?;
+
+        self.write_register(config.pwmconf)?;
+        let (_, mut gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        gconf.set_en_pwm_mode(config.stealthchop);
+        self.write_register(gconf)?;
+        // Freewheeling depends on StealthChop, so it is configured after `GCONF`.
+        self.set_standstill(&config.standstill)?;
+        self.set_velocity_thresholds(&config.thresholds)?;
+        self.set_ramp(&config.ramp)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::INTERNAL_CLOCK_HZ;
+
+    #[test]
+    fn presets_recommend_valid_configs() {
+        for profile in [MotorProfile::NEMA17_40MM, MotorProfile::NEMA17_48MM, MotorProfile::NEMA23_56MM, MotorProfile::NEMA23_76MM] {
+            let config = profile.recommend(24.0, INTERNAL_CLOCK_HZ).unwrap();
+            assert!(config.run_current_rms < profile.rated_current_a);
+            assert!(config.stealthchop);
+            assert!(config.ramp.vmax > 0 && config.ramp.amax > 0);
+        }
+    }
+
+    #[test]
+    fn low_supply_disables_stealthchop() {
+        let config = MotorProfile::NEMA23_76MM.recommend(5.0, INTERNAL_CLOCK_HZ).unwrap();
+        assert!(!config.stealthchop);
+        assert_eq!(config.thresholds.stealthchop_max, None);
+    }
+}
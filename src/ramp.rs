@@ -0,0 +1,180 @@
+//! Description of the six point ramp of the internal ramp generator.
+//!
+//! The ramp accelerates from `VSTART` with `A1` up to `V1`, then with `AMAX` up to `VMAX`, and
+//! decelerates with `DMAX` down to `V1`, then with `D1` down to `VSTOP`. Setting `V1` to zero skips
+//! the `A1` and `D1` phases, which leaves a trapezoidal ramp. All values are in the units of the
+//! ramp generator registers, which depend on the clock frequency fCLK:
+//!
+//! * velocity in microsteps per second is `v * fCLK / 2^24`.
+//! * acceleration in microsteps per second squared is `a * fCLK^2 / 2^41`.
+//!
+//! The velocity thresholds `TPWMTHRS`, `TCOOLTHRS` and `THIGH` are compared against `TSTEP`, the
+//! time between two 1/256 microsteps in clock cycles, which is inverse to the velocity.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address, State};
+use crate::{Action, Error, Tmc5130};
+
+/// The parameters of the six point ramp, in register units.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct RampConfig {
+    /// Start velocity `VSTART`.
+    pub vstart: u32,
+    /// First acceleration `A1`, used below `V1`.
+    pub a1: u16,
+    /// Velocity `V1` at which acceleration switches from `A1` to `AMAX`, 0 to disable.
+    pub v1: u32,
+    /// Acceleration `AMAX`, used above `V1`.
+    pub amax: u16,
+    /// Target velocity `VMAX`.
+    pub vmax: u32,
+    /// Deceleration `DMAX`, used above `V1`.
+    pub dmax: u16,
+    /// Final deceleration `D1`, used below `V1`. Must not be zero in positioning mode.
+    pub d1: u16,
+    /// Stop velocity `VSTOP`, at least `VSTART` and at least 1.
+    pub vstop: u32,
+}
+
+impl Default for RampConfig {
+    /// The example ramp of the datasheet.
+    fn default() -> Self {
+        Self {
+            vstart: 0,
+            a1: 1_000,
+            v1: 50_000,
+            amax: 500,
+            vmax: 200_000,
+            dmax: 700,
+            d1: 1_400,
+            vstop: 10,
+        }
+    }
+}
+
+/// Converts a ramp generator velocity to microsteps per second.
+pub fn velocity_to_hz(velocity: u32, clock_hz: u32) -> f32 {
+    velocity as f32 * clock_hz as f32 / (1u32 << 24) as f32
+}
+
+/// Converts microsteps per second to a ramp generator velocity.
+pub fn velocity_from_hz(hz: f32, clock_hz: u32) -> u32 {
+    (hz * (1u32 << 24) as f32 / clock_hz as f32 + 0.5) as u32
+}
+
+/// Converts a ramp generator acceleration to microsteps per second squared.
+pub fn acceleration_to_hz_per_s(acceleration: u16, clock_hz: u32) -> f32 {
+    let clock = clock_hz as f32;
+    acceleration as f32 * clock * clock / (1u64 << 41) as f32
+}
+
+/// Converts microsteps per second squared to a ramp generator acceleration.
+pub fn acceleration_from_hz_per_s(hz_per_s: f32, clock_hz: u32) -> u32 {
+    let clock = clock_hz as f32;
+    (hz_per_s * (1u64 << 41) as f32 / (clock * clock) + 0.5) as u32
+}
+
+/// The number of microsteps per full step for a `CHOPCONF::mres` setting.
+pub fn microsteps(mres: u32) -> u32 {
+    256 >> mres.min(8)
+}
+
+/// Converts a velocity in microsteps per second at the given `mres` to a `TSTEP` value.
+///
+/// Zero velocity gives the largest `TSTEP`, 2^20 - 1.
+pub fn tstep_from_hz(hz: f32, mres: u32, clock_hz: u32) -> u32 {
+    const TSTEP_MAX: u32 = (1 << 20) - 1;
+    let fine_steps_per_s = hz * (256 / microsteps(mres)) as f32;
+    if fine_steps_per_s <= 0.0 {
+        return TSTEP_MAX;
+    }
+    let tstep = clock_hz as f32 / fine_steps_per_s;
+    if tstep >= TSTEP_MAX as f32 { TSTEP_MAX } else { (tstep + 0.5) as u32 }
+}
+
+/// Converts a `TSTEP` value to a velocity in microsteps per second at the given `mres`.
+pub fn tstep_to_hz(tstep: u32, mres: u32, clock_hz: u32) -> f32 {
+    if tstep == 0 {
+        return f32::INFINITY;
+    }
+    clock_hz as f32 / tstep as f32 / (256 / microsteps(mres)) as f32
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Writes all ramp parameters to the internal ramp generator in one batch.
+    pub fn set_ramp(&mut self, ramp: &RampConfig) -> Result<reg::SPISTATUS, Error<SPI::Error>> {
+        let mut vstart = reg::VSTART::default();
+        vstart.set(ramp.vstart);
+        let mut a1 = reg::A1::default();
+        a1.set(ramp.a1);
+        let mut v1 = reg::V1::default();
+        v1.set(ramp.v1);
+        let mut amax = reg::AMAX::default();
+        amax.set(ramp.amax);
+        let mut vmax = reg::VMAX::default();
+        vmax.set(ramp.vmax);
+        let mut dmax = reg::DMAX::default();
+        dmax.set(ramp.dmax);
+        let mut d1 = reg::D1::default();
+        d1.set(ramp.d1);
+        let mut vstop = reg::VSTOP::default();
+        vstop.set(ramp.vstop);
+        let states: [State; 8] = [vstart.into(), a1.into(), v1.into(), amax.into(), vmax.into(), dmax.into(), d1.into(), vstop.into()];
+        let mut actions = states.each_ref().map(Action::write);
+        self.bulk_register_action(&mut actions)
+    }
+
+    /// The ramp parameters last written to the chip.
+    pub fn ramp(&self) -> RampConfig {
+        RampConfig {
+            vstart: self.shadow.vstart().get(),
+            a1: self.shadow.a1().get(),
+            v1: self.shadow.v1().get(),
+            amax: self.shadow.amax().get(),
+            vmax: self.shadow.vmax().get(),
+            dmax: self.shadow.dmax().get(),
+            d1: self.shadow.d1().get(),
+            vstop: self.shadow.vstop().get(),
+        }
+    }
+
+    /// Stops the motor with the deceleration ramp and holds it where it stopped.
+    ///
+    /// Setting `VMAX` to zero makes the ramp generator decelerate in both positioning and velocity
+    /// mode. Once at standstill, `XTARGET` is moved to the stop position, the ramp generator is
+    /// switched to positioning mode and `VMAX` restored.
+    pub fn stop<D>(&mut self, delay: &mut D, poll_interval_us: u32, timeout_ms: u32) -> Result<(), Error<SPI::Error>>
+    where D: DelayNs
+    {
+        self.stop_with_ramp(delay, poll_interval_us, timeout_ms, true)
+    }
+
+    /// Decelerates with `VMAX` zero, then moves `XTARGET` to the stop position, switches to
+    /// positioning mode if `positioning` is set and restores `VMAX`.
+    ///
+    /// The stop position is written in chip coordinates, so neither the soft limits nor the
+    /// backlash compensation move it, and stopping short of the target is not taken for a reversal.
+    pub(crate) fn stop_with_ramp<D>(&mut self, delay: &mut D, poll_interval_us: u32, timeout_ms: u32, positioning: bool) -> Result<(), Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let vmax = *self.shadow.vmax();
+        self.write_register(reg::VMAX::default())?;
+        self.poll_ramp_stat(delay, poll_interval_us, timeout_ms, |ramp_stat| ramp_stat.vzero())?
+            .ok_or(Error::Timeout)?;
+        // The shadow keeps the position read in chip coordinates.
+        self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        let xactual = self.shadow.xactual().0;
+        self.write_raw(Address::XTARGET, xactual).map_err(Error::Spi)?;
+        if positioning {
+            self.write_register(reg::RAMPMODE::POSITIONING)?;
+        }
+        self.write_register(vmax)?;
+        Ok(())
+    }
+}
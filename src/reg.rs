@@ -180,7 +180,7 @@ bitfield! {
     #[cfg_attr(feature = "ufmt", derive(ufmt::derive::uDebug))]
     pub struct TPOWERDOWN(u32);
     impl Debug;
-    i32;
+    u8;
     pub get, set: 7, 0;
 }
 
@@ -1056,10 +1056,10 @@ impl_registers! {
     R  0x02 IFCNT ifcnt ifcnt_mut,
     W  0x03 SLAVECONF slaveconf slaveconf_mut,
     R  0x04 IOIN ioin ioin_mut,
-    R  0x05 X_COMPARE x_compare x_compare_mut,
+    W  0x05 X_COMPARE x_compare x_compare_mut,
     W  0x10 IHOLD_IRUN ihold_irun ihold_irun_mut,
     W  0x11 TPOWERDOWN tpowerdown tpowerdown_mut,
-    W  0x12 TSTEP tstep tstep_mut,
+    R  0x12 TSTEP tstep tstep_mut,
     W  0x13 TPWMTHRS tpwmthrs tpwmthrs_mut,
     W  0x14 TCOOLTHRS tcoolthrs tcoolthrs_mut,
     W  0x15 THIGH thigh thigh_mut,
@@ -1109,6 +1109,17 @@ impl VACTUAL {
     pub const ENABLED_STOPPED: Self = VACTUAL(1);
 }
 
+impl RAMPMODE {
+    /// Positioning mode, moves towards `XTARGET` using all A, D and V parameters.
+    pub const POSITIONING: Self = RAMPMODE(0);
+    /// Velocity mode to positive `VMAX`, using `AMAX` acceleration.
+    pub const VELOCITY_POSITIVE: Self = RAMPMODE(1);
+    /// Velocity mode to negative `VMAX`, using `AMAX` acceleration.
+    pub const VELOCITY_NEGATIVE: Self = RAMPMODE(2);
+    /// Hold mode, the velocity remains unchanged unless a stop event occurs.
+    pub const HOLD: Self = RAMPMODE(3);
+}
+
 // Default Register States (taken from TMC-API reference).
 // --------------------------------------------------------
 
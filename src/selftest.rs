@@ -0,0 +1,300 @@
+//! Self test of the motor wiring.
+//!
+//! The driver flags a short to ground of a coil in `DRV_STATUS::s2ga` and `s2gb`, and an open coil
+//! in `ola` and `olb`. The open load flags are only valid while the motor moves slowly in
+//! SpreadCycle, as at standstill no current change is expected and at speed the back EMF limits the
+//! current anyway. The self test therefore drives a short, slow positioning move with a known
+//! current, samples the flags together with `MSCURACT` and returns to the start position.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address};
+use crate::{Error, Tmc5130};
+
+/// `VSTOP` of the test move, the ramp generator requires at least 1 in positioning mode.
+const TEST_VSTOP: u32 = 10;
+
+/// Parameters of the self test.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SelfTest {
+    /// Current scale used as run and hold current during the test, 0 to 31.
+    pub current_scale: u8,
+    /// Distance of the test move in microsteps, its sign gives the direction.
+    pub distance: i32,
+    /// Velocity of the test move, written to `VMAX`.
+    pub velocity: u32,
+    /// Acceleration of the test move, written to `AMAX`, `DMAX` and `D1`.
+    pub acceleration: u16,
+    /// Interval between two samples, in milliseconds.
+    pub sample_interval_ms: u32,
+    /// Time allowed for each of the two moves, in milliseconds.
+    pub timeout_ms: u32,
+}
+
+impl Default for SelfTest {
+    fn default() -> Self {
+        Self {
+            current_scale: 16,
+            distance: 3_200,
+            velocity: 5_000,
+            acceleration: 500,
+            sample_interval_ms: 5,
+            timeout_ms: 5_000,
+        }
+    }
+}
+
+/// The result of the self test for one coil.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct CoilReport {
+    /// Number of samples flagging an open load.
+    pub open_load_samples: u16,
+    /// A short to ground was flagged in any sample.
+    pub short_to_ground: bool,
+    /// Highest absolute target current from `MSCURACT` seen while moving, up to 255.
+    pub peak_current: u16,
+}
+
+impl CoilReport {
+    /// Whether the coil passed: no short, and open load flagged in at most half of the samples.
+    pub fn passed(&self, samples: u16) -> bool {
+        !self.short_to_ground && self.open_load_samples <= samples / 2
+    }
+}
+
+/// The result of the self test.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SelfTestReport {
+    /// Coil A.
+    pub coil_a: CoilReport,
+    /// Coil B.
+    pub coil_b: CoilReport,
+    /// Number of samples taken while moving.
+    pub samples: u16,
+    /// The overtemperature shutdown was flagged in any sample.
+    pub overtemperature: bool,
+}
+
+impl SelfTestReport {
+    /// Whether both coils passed and the driver did not overheat.
+    pub fn passed(&self) -> bool {
+        self.samples > 0 && !self.overtemperature && self.coil_a.passed(self.samples) && self.coil_b.passed(self.samples)
+    }
+}
+
+/// Sign extends a 9 bit `MSCURACT` field and returns its magnitude.
+fn current_magnitude(cur: u16) -> u16 {
+    (((cur << 7) as i16) >> 7).unsigned_abs()
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Runs the wiring self test.
+    ///
+    /// The motor has to be at standstill. The driver is enabled in SpreadCycle with the test
+    /// current, with a default chopper configuration if `CHOPCONF::toff` disabled it. The test move
+    /// uses a trapezoidal ramp from standstill and its targets are written in chip coordinates, so
+    /// neither the soft limits nor the backlash compensation change it. Afterwards the motor is
+    /// back at its start position, with `XTARGET` on it. `GCONF` and `CHOPCONF` are restored to the
+    /// values read before the test, the write-only `IHOLD_IRUN` and ramp registers and `RAMPMODE`
+    /// from the shadow. An error restoring them is only returned if the test itself succeeded.
+    pub fn self_test<D>(&mut self, test: &SelfTest, delay: &mut D) -> Result<SelfTestReport, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let (_, ramp_stat) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+        if !ramp_stat.vzero() {
+            return Err(Error::NotAtStandstill);
+        }
+        // The shadow keeps the position read in chip coordinates.
+        self.read_register::<reg::XACTUAL>().map_err(Error::Spi)?;
+        let start = self.shadow.xactual().get();
+        let (_, gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        let (_, chopconf) = self.read_register::<reg::CHOPCONF>().map_err(Error::Spi)?;
+        let ihold_irun = *self.shadow.ihold_irun();
+        let vmax = *self.shadow.vmax();
+        let amax = *self.shadow.amax();
+        let dmax = *self.shadow.dmax();
+        let vstart = *self.shadow.vstart();
+        let v1 = *self.shadow.v1();
+        let d1 = *self.shadow.d1();
+        let vstop = *self.shadow.vstop();
+        let rampmode = *self.shadow.rampmode();
+
+        let result = self.self_test_inner(test, start, gconf, chopconf, delay);
+        // Return to the start position even if sampling failed.
+        let returned = self.write_raw(Address::XTARGET, start as u32).map_err(Error::Spi).and_then(|_| {
+            self.poll_ramp_stat(delay, test.sample_interval_ms * 1000, test.timeout_ms, |ramp_stat| ramp_stat.position_reached())?
+                .ok_or(Error::Timeout)
+        });
+
+        let restored = self.write_register(ihold_irun)
+            .and_then(|_| self.write_register(chopconf))
+            .and_then(|_| self.write_register(gconf))
+            .and_then(|_| self.write_register(dmax))
+            .and_then(|_| self.write_register(amax))
+            .and_then(|_| self.write_register(vstart))
+            .and_then(|_| self.write_register(v1))
+            .and_then(|_| self.write_register(d1))
+            .and_then(|_| self.write_register(vstop))
+            .and_then(|_| self.write_register(vmax))
+            .and_then(|_| self.write_register(rampmode));
+        let report = result?;
+        returned?;
+        restored?;
+        Ok(report)
+    }
+
+    fn self_test_inner<D>(&mut self, test: &SelfTest, start: i32, mut gconf: reg::GCONF, mut chopconf: reg::CHOPCONF, delay: &mut D) -> Result<SelfTestReport, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        if chopconf.toff() == 0 {
+            chopconf.set_toff(3);
+            chopconf.set_tbl(2);
+        }
+        self.write_register(chopconf)?;
+        gconf.set_en_pwm_mode(false);
+        self.write_register(gconf)?;
+        let mut ihold_irun = *self.shadow.ihold_irun();
+        ihold_irun.set_irun(test.current_scale.min(31));
+        ihold_irun.set_ihold(test.current_scale.min(31));
+        self.write_register(ihold_irun)?;
+
+        let mut amax = reg::AMAX::default();
+        amax.set(test.acceleration);
+        self.write_register(amax)?;
+        let mut dmax = reg::DMAX::default();
+        dmax.set(test.acceleration);
+        self.write_register(dmax)?;
+        // A trapezoidal ramp, which still needs `D1` and `VSTOP` in positioning mode.
+        self.write_register(reg::VSTART::default())?;
+        self.write_register(reg::V1::default())?;
+        let mut d1 = reg::D1::default();
+        d1.set(test.acceleration.max(1));
+        self.write_register(d1)?;
+        let mut vstop = reg::VSTOP::default();
+        vstop.set(TEST_VSTOP);
+        self.write_register(vstop)?;
+        let mut vmax = reg::VMAX::default();
+        vmax.set(test.velocity);
+        self.write_register(vmax)?;
+        self.write_register(reg::RAMPMODE::POSITIONING)?;
+        self.write_raw(Address::XTARGET, start.wrapping_add(test.distance) as u32).map_err(Error::Spi)?;
+
+        let mut report = SelfTestReport::default();
+        let mut elapsed_ms = 0;
+        loop {
+            delay.delay_ms(test.sample_interval_ms);
+            elapsed_ms += test.sample_interval_ms.max(1);
+            let (_, ramp_stat) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+            if ramp_stat.position_reached() {
+                break;
+            }
+            if elapsed_ms >= test.timeout_ms {
+                return Err(Error::Timeout);
+            }
+            let (_, drv_status) = self.read_register::<reg::DRV_STATUS>().map_err(Error::Spi)?;
+            let (_, mscuract) = self.read_register::<reg::MSCURACT>().map_err(Error::Spi)?;
+            report.samples = report.samples.saturating_add(1);
+            report.overtemperature |= drv_status.ot();
+            report.coil_a.open_load_samples += drv_status.ola() as u16;
+            report.coil_b.open_load_samples += drv_status.olb() as u16;
+            report.coil_a.short_to_ground |= drv_status.s2ga();
+            report.coil_b.short_to_ground |= drv_status.s2gb();
+            report.coil_a.peak_current = report.coil_a.peak_current.max(current_magnitude(mscuract.cur_a()));
+            report.coil_b.peak_current = report.coil_b.peak_current.max(current_magnitude(mscuract.cur_b()));
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+
+    use super::*;
+    use crate::backlash::Backlash;
+    use crate::mock_peripherals::{driver, read_register, write, VZERO};
+
+    const POSITION_REACHED: u32 = 1 << 9;
+
+    #[test]
+    fn samples_while_moving_and_restores() {
+        let test = SelfTest::default();
+        let mut datagrams = read_register(Address::RAMP_STAT, VZERO).to_vec();
+        datagrams.extend(read_register(Address::XACTUAL, 1_000));
+        datagrams.extend(read_register(Address::GCONF, 0b100));
+        // `toff` 0 disables the driver.
+        datagrams.extend(read_register(Address::CHOPCONF, 0));
+        datagrams.extend([
+            write(Address::CHOPCONF, 3 | 2 << 15, 0),
+            write(Address::GCONF, 0, 0),
+            write(Address::IHOLD_IRUN, 0x1010, 0),
+            write(Address::AMAX, 500, 0),
+            write(Address::DMAX, 500, 0),
+            write(Address::VSTART, 0, 0),
+            write(Address::V1, 0, 0),
+            write(Address::D1, 500, 0),
+            write(Address::VSTOP, TEST_VSTOP, 0),
+            write(Address::VMAX, 5_000, 0),
+            write(Address::RAMPMODE, 0, 0),
+            // In chip coordinates, the backlash offset is not applied.
+            write(Address::XTARGET, 4_200, 0),
+        ]);
+        datagrams.extend(read_register(Address::RAMP_STAT, 0));
+        // `ola` and `s2gb`.
+        datagrams.extend(read_register(Address::DRV_STATUS, 1 << 29 | 1 << 28));
+        datagrams.extend(read_register(Address::MSCURACT, (-200i32 as u32 & 0x1FF) | 100 << 16));
+        datagrams.extend(read_register(Address::RAMP_STAT, POSITION_REACHED));
+        datagrams.push(write(Address::XTARGET, 1_000, 0));
+        datagrams.extend(read_register(Address::RAMP_STAT, POSITION_REACHED));
+        datagrams.extend([
+            write(Address::IHOLD_IRUN, 0x1F00, 0),
+            write(Address::CHOPCONF, 0, 0),
+            write(Address::GCONF, 0b100, 0),
+            write(Address::DMAX, 0, 0),
+            write(Address::AMAX, 0, 0),
+            write(Address::VSTART, 0, 0),
+            write(Address::V1, 0, 0),
+            write(Address::D1, 0, 0),
+            write(Address::VSTOP, 0, 0),
+            write(Address::VMAX, 0, 0),
+            write(Address::RAMPMODE, 0, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.backlash = Some(Backlash { distance: 10, offset: 10, direction: Some(crate::Direction::Negative) });
+        let report = driver.self_test(&test, &mut NoopDelay::new()).unwrap();
+        assert_eq!(report.samples, 1);
+        assert_eq!(report.coil_a, CoilReport { open_load_samples: 1, short_to_ground: false, peak_current: 200 });
+        assert_eq!(report.coil_b, CoilReport { open_load_samples: 0, short_to_ground: true, peak_current: 100 });
+        assert!(!report.passed());
+        spi.done();
+    }
+
+    #[test]
+    fn classification() {
+        let coil = CoilReport { open_load_samples: 5, short_to_ground: false, peak_current: 180 };
+        assert!(coil.passed(10));
+        assert!(!CoilReport { open_load_samples: 6, ..coil }.passed(10));
+        assert!(!CoilReport { short_to_ground: true, ..coil }.passed(10));
+
+        let report = SelfTestReport { coil_a: coil, coil_b: coil, samples: 10, overtemperature: false };
+        assert!(report.passed());
+        assert!(!SelfTestReport { overtemperature: true, ..report }.passed());
+        assert!(!SelfTestReport { coil_b: CoilReport { short_to_ground: true, ..coil }, ..report }.passed());
+        // Nothing was measured.
+        assert!(!SelfTestReport::default().passed());
+    }
+
+    #[test]
+    fn current_magnitude_sign_extends() {
+        assert_eq!(current_magnitude(255), 255);
+        assert_eq!(current_magnitude(-255i16 as u16 & 0x1FF), 255);
+        assert_eq!(current_magnitude(0), 0);
+    }
+}
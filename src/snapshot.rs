@@ -0,0 +1,131 @@
+//! A snapshot of all registers.
+//!
+//! The replies of the chip are pipelined: each datagram returns the data requested by the previous
+//! one. [`Tmc5130::snapshot`] makes use of this by sending the read requests for all readable
+//! registers back to back, taking each value from the following reply, so `N` registers cost
+//! `N + 1` datagrams instead of the `2N` of separate [`Tmc5130::read_register`] calls. Write-only
+//! registers can not be read back, their entries are taken from the shadow of the last writes.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address, State};
+use crate::{Error, Tmc5130};
+
+/// The state of all registers at one point in time.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Snapshot {
+    /// The register values. Live entries hold the values read from the chip, with positions
+    /// compensated for backlash as by [`Tmc5130::read_register`], cached entries the last values
+    /// written.
+    pub registers: reg::Map,
+    /// The status returned with the last datagram.
+    pub status: reg::SPISTATUS,
+    /// One bit per entry of `Address::ALL`, set for entries read from the chip.
+    live: u64,
+}
+
+impl Snapshot {
+    /// Whether the entry of `addr` was read from the chip, as opposed to taken from the shadow.
+    pub fn is_live(&self, addr: Address) -> bool {
+        index(addr).is_some_and(|index| self.live & (1 << index) != 0)
+    }
+
+    /// The entries read from the chip.
+    pub fn live(&self) -> impl Iterator<Item = &State> + '_ {
+        Address::ALL.iter().filter(|&&addr| self.is_live(addr)).map(|&addr| self.registers.state(addr))
+    }
+
+    /// The entries taken from the shadow.
+    pub fn cached(&self) -> impl Iterator<Item = &State> + '_ {
+        Address::ALL.iter().filter(|&&addr| !self.is_live(addr)).map(|&addr| self.registers.state(addr))
+    }
+}
+
+impl From<Snapshot> for reg::Map {
+    fn from(snapshot: Snapshot) -> Self {
+        snapshot.registers
+    }
+}
+
+// The live entries are tracked in a `u64`.
+const _: () = assert!(reg::COUNT <= 64);
+
+fn index(addr: Address) -> Option<usize> {
+    Address::ALL.iter().position(|&other| other == addr)
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Reads all readable registers in one pipelined sequence and fills in the write-only ones
+    /// from the shadow.
+    ///
+    /// The shadow is updated with the raw values read. Reading `RAMP_STAT` clears its event flags,
+    /// as any read of it does.
+    pub fn snapshot(&mut self) -> Result<Snapshot, Error<SPI::Error>> {
+        let mut registers = self.shadow.clone();
+        let mut live = 0;
+        let mut status = reg::SPISTATUS(0);
+        let mut previous: Option<(usize, Address)> = None;
+        for (index, &addr) in Address::ALL.iter().enumerate().filter(|(_, addr)| addr.readable()) {
+            let (datagram_status, data) = self.read_raw(addr).map_err(Error::Spi)?;
+            status = datagram_status;
+            if let Some((previous_index, previous_addr)) = previous {
+                self.store_snapshot_entry(&mut registers, previous_addr, data);
+                live |= 1 << previous_index;
+            }
+            previous = Some((index, addr));
+        }
+        // One more datagram collects the reply to the last request.
+        if let Some((index, addr)) = previous {
+            let (datagram_status, data) = self.read_raw(addr).map_err(Error::Spi)?;
+            status = datagram_status;
+            self.store_snapshot_entry(&mut registers, addr, data);
+            live |= 1 << index;
+        }
+        Ok(Snapshot { registers, status, live })
+    }
+
+    fn store_snapshot_entry(&mut self, registers: &mut reg::Map, addr: Address, data: u32) {
+        self.shadow.set_state(State::from_addr_and_data(addr, data));
+        registers.set_state(State::from_addr_and_data(addr, self.backlash_read(addr, data)));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backlash::Backlash;
+    use crate::mock_peripherals::{driver, read};
+
+    #[test]
+    fn reads_each_register_once_plus_one() {
+        let readable: Vec<Address> = Address::ALL.iter().copied().filter(Address::readable).collect();
+        let value = |addr: Address| 0x100 + addr as u32;
+        // Each request is answered with the value of the previous one.
+        let mut datagrams = vec![read(readable[0], 0)];
+        datagrams.extend(readable.windows(2).map(|pair| read(pair[1], value(pair[0]))));
+        let last = *readable.last().unwrap();
+        datagrams.push(read(last, value(last)));
+        assert_eq!(datagrams.len(), readable.len() + 1);
+
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.backlash = Some(Backlash { distance: 10, offset: 10, direction: None });
+        let mut vmax = reg::VMAX::default();
+        vmax.set(5_000);
+        driver.shadow.set_state(vmax.into());
+
+        let snapshot = driver.snapshot().unwrap();
+        for &addr in Address::ALL {
+            assert_eq!(snapshot.is_live(addr), addr.readable());
+        }
+        assert_eq!(snapshot.registers.gconf().0, value(Address::GCONF));
+        assert_eq!(snapshot.registers.vmax().get(), 5_000);
+        // Positions are compensated, the shadow keeps the chip value.
+        assert_eq!(snapshot.registers.xactual().get(), value(Address::XACTUAL) as i32 - 10);
+        assert_eq!(driver.shadow().xactual().get(), value(Address::XACTUAL) as i32);
+        assert_eq!(snapshot.live().count() + snapshot.cached().count(), reg::COUNT);
+        spi.done();
+    }
+}
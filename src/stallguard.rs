@@ -0,0 +1,307 @@
+//! Calibration of the StallGuard2 threshold `COOLCONF::sgt`.
+//!
+//! `SG_RESULT` falls with the load on the motor and reaches 0 at a stall, and `sgt` offsets it:
+//! higher values make StallGuard2 less sensitive. A good `sgt` puts the unloaded `SG_RESULT` well
+//! inside its range, so the load can pull it down to 0 before the motor actually loses steps. The
+//! calibration runs the unloaded motor in SpreadCycle, steps `sgt` through its signed range and
+//! records `SG_RESULT` statistics for each value.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Direction, Error, Tmc5130};
+
+/// The number of `sgt` values, -64 to 63.
+pub const SGT_VALUES: usize = 128;
+
+/// Parameters of the `sgt` calibration sweep.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SgtCalibration {
+    /// Velocity of the calibration run, written to `VMAX`.
+    pub velocity: u32,
+    /// Acceleration of the calibration run, written to `AMAX`.
+    pub acceleration: u16,
+    /// Direction of the calibration run.
+    pub direction: Direction,
+    /// The first `sgt` of the sweep.
+    pub sgt_first: i8,
+    /// The last `sgt` of the sweep.
+    pub sgt_last: i8,
+    /// Increment between two `sgt` values of the sweep.
+    pub sgt_step: u8,
+    /// Enables the StallGuard2 filter `COOLCONF::sfilt` during the sweep.
+    pub sfilt: bool,
+    /// Time for `SG_RESULT` to settle after changing `sgt`, in milliseconds.
+    pub settle_ms: u32,
+    /// Number of `DRV_STATUS` samples for each `sgt`.
+    pub samples: u16,
+    /// Interval between two samples, in milliseconds.
+    pub sample_interval_ms: u32,
+    /// Lowest unloaded mean `SG_RESULT` of the target window.
+    pub target_min: u16,
+    /// Highest unloaded mean `SG_RESULT` of the target window.
+    pub target_max: u16,
+    /// Time allowed to reach the velocity, in milliseconds.
+    pub timeout_ms: u32,
+}
+
+impl Default for SgtCalibration {
+    fn default() -> Self {
+        Self {
+            velocity: 100_000,
+            acceleration: 1_000,
+            direction: Direction::Positive,
+            sgt_first: -64,
+            sgt_last: 63,
+            sgt_step: 1,
+            sfilt: false,
+            settle_ms: 20,
+            samples: 16,
+            sample_interval_ms: 2,
+            target_min: 300,
+            target_max: 700,
+            timeout_ms: 5_000,
+        }
+    }
+}
+
+/// `SG_RESULT` statistics for one `sgt` value.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SgtSample {
+    /// The threshold the statistics were taken with.
+    pub sgt: i8,
+    /// Mean `SG_RESULT`.
+    pub mean: u16,
+    /// Lowest `SG_RESULT`.
+    pub min: u16,
+    /// Highest `SG_RESULT`.
+    pub max: u16,
+}
+
+/// The raw table of a sweep and the recommended threshold.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct SgtSweep {
+    entries: [SgtSample; SGT_VALUES],
+    len: usize,
+    /// The `sgt` whose mean `SG_RESULT` is closest to the middle of the target window, among those
+    /// inside the window that never read 0. `None` if no value qualifies.
+    pub recommended: Option<i8>,
+}
+
+impl SgtSweep {
+    /// The statistics of each `sgt` value in the order they were measured.
+    pub fn entries(&self) -> &[SgtSample] {
+        &self.entries[..self.len]
+    }
+
+    fn recommend(&mut self, target_min: u16, target_max: u16) {
+        let centre = (target_min as i32 + target_max as i32) / 2;
+        self.recommended = self.entries()
+            .iter()
+            .filter(|sample| sample.min > 0 && (target_min..=target_max).contains(&sample.mean))
+            .min_by_key(|sample| (sample.mean as i32 - centre).abs())
+            .map(|sample| sample.sgt);
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Sweeps `sgt` on the running, unloaded motor and recommends a threshold.
+    ///
+    /// CoolStep is disabled during the sweep, so all samples are taken at the run current. The
+    /// recommended value is not written, `GCONF`, `COOLCONF`, `TCOOLTHRS`, `VMAX` and `AMAX` are
+    /// restored and the motor stopped afterwards.
+    pub fn calibrate_sgt<D>(&mut self, calibration: &SgtCalibration, delay: &mut D) -> Result<SgtSweep, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let (_, gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        let coolconf = *self.shadow.coolconf();
+        let tcoolthrs = *self.shadow.tcoolthrs();
+        let vmax = *self.shadow.vmax();
+        let amax = *self.shadow.amax();
+
+        let result = self.calibrate_sgt_inner(calibration, gconf, coolconf, delay);
+        let stopped = self.stop(delay, calibration.sample_interval_ms * 1000, calibration.timeout_ms);
+
+        self.write_register(amax)?;
+        self.write_register(vmax)?;
+        self.write_register(tcoolthrs)?;
+        self.write_register(coolconf)?;
+        self.write_register(gconf)?;
+        stopped?;
+        result
+    }
+
+    fn calibrate_sgt_inner<D>(&mut self, calibration: &SgtCalibration, gconf: reg::GCONF, coolconf: reg::COOLCONF, delay: &mut D) -> Result<SgtSweep, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let mut spread_cycle = gconf;
+        spread_cycle.set_en_pwm_mode(false);
+        self.write_register(spread_cycle)?;
+        let mut tcoolthrs = reg::TCOOLTHRS::default();
+        tcoolthrs.set((1 << 20) - 1);
+        self.write_register(tcoolthrs)?;
+        let mut sweep_coolconf = coolconf;
+        sweep_coolconf.set_semin(0);
+        sweep_coolconf.set_sfilt(calibration.sfilt);
+
+        let mut amax = reg::AMAX::default();
+        amax.set(calibration.acceleration);
+        self.write_register(amax)?;
+        let mut vmax = reg::VMAX::default();
+        vmax.set(calibration.velocity);
+        self.write_register(vmax)?;
+        self.write_register(calibration.direction.velocity_mode())?;
+        self.poll_ramp_stat(delay, calibration.sample_interval_ms * 1000, calibration.timeout_ms, |ramp_stat| ramp_stat.velocity_reached())?
+            .ok_or(Error::Timeout)?;
+
+        let mut sweep = SgtSweep { entries: [SgtSample::default(); SGT_VALUES], len: 0, recommended: None };
+        let first = calibration.sgt_first.clamp(-64, 63) as i16;
+        let last = calibration.sgt_last.clamp(-64, 63) as i16;
+        let step = calibration.sgt_step.max(1) as usize;
+        let samples = calibration.samples.max(1);
+        for sgt in (first..=last).step_by(step) {
+            sweep_coolconf.set_sgt(sgt as i8);
+            self.write_register(sweep_coolconf)?;
+            delay.delay_ms(calibration.settle_ms);
+
+            let mut sum = 0u32;
+            let mut sample = SgtSample { sgt: sgt as i8, mean: 0, min: u16::MAX, max: 0 };
+            for _ in 0..samples {
+                delay.delay_ms(calibration.sample_interval_ms);
+                let (_, drv_status) = self.read_register::<reg::DRV_STATUS>().map_err(Error::Spi)?;
+                let sg = drv_status.sg_result() as u16;
+                sum += sg as u32;
+                sample.min = sample.min.min(sg);
+                sample.max = sample.max.max(sg);
+            }
+            sample.mean = (sum / samples as u32) as u16;
+            sweep.entries[sweep.len] = sample;
+            sweep.len += 1;
+        }
+        sweep.recommend(calibration.target_min, calibration.target_max);
+        Ok(sweep)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+    use embedded_hal_mock::eh1::spi::Mock;
+
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write, Datagram, VZERO};
+    use crate::reg::{Address, State};
+
+    const EN_PWM_MODE: u32 = 1 << 2;
+    const VELOCITY_REACHED: u32 = 1 << 8;
+
+    fn calibration() -> SgtCalibration {
+        SgtCalibration { velocity: 50_000, acceleration: 800, sgt_first: -1, sgt_last: 1, sfilt: true, samples: 2, ..Default::default() }
+    }
+
+    /// The original `COOLCONF`, with CoolStep enabled and `sgt` 5.
+    fn coolconf() -> reg::COOLCONF {
+        let mut coolconf = reg::COOLCONF::default();
+        coolconf.set_semin(2);
+        coolconf.set_semax(4);
+        coolconf.set_sgt(5);
+        coolconf
+    }
+
+    /// Starts the run from a `GCONF` with StealthChop enabled.
+    fn start(ramp_stat: u32) -> Vec<Datagram> {
+        let mut datagrams = read_register(Address::GCONF, EN_PWM_MODE).to_vec();
+        datagrams.extend([
+            write(Address::GCONF, 0, 0),
+            write(Address::TCOOLTHRS, (1 << 20) - 1, 0),
+            write(Address::AMAX, 800, 0),
+            write(Address::VMAX, 50_000, 0),
+            write(Address::RAMPMODE, 1, 0),
+        ]);
+        datagrams.extend(read_register(Address::RAMP_STAT, ramp_stat));
+        datagrams
+    }
+
+    /// Stops the motor and restores the registers.
+    fn stop_and_restore() -> Vec<Datagram> {
+        let mut datagrams = vec![write(Address::VMAX, 0, 0)];
+        datagrams.extend(read_register(Address::RAMP_STAT, VZERO));
+        datagrams.extend(read_register(Address::XACTUAL, 5_000));
+        datagrams.extend([
+            write(Address::XTARGET, 5_000, 0),
+            write(Address::RAMPMODE, 0, 0),
+            write(Address::VMAX, 50_000, 0),
+            write(Address::AMAX, 500, 0),
+            write(Address::VMAX, 2_000, 0),
+            write(Address::TCOOLTHRS, 100, 0),
+            write(Address::COOLCONF, coolconf().0, 0),
+            write(Address::GCONF, EN_PWM_MODE, 0),
+        ]);
+        datagrams
+    }
+
+    /// The registers before the calibration.
+    fn set_shadow(driver: &mut Tmc5130<Mock<u8>>) {
+        driver.shadow.set_state(coolconf().into());
+        driver.shadow.set_state(State::from_addr_and_data(Address::TCOOLTHRS, 100));
+        driver.shadow.set_state(State::from_addr_and_data(Address::VMAX, 2_000));
+        driver.shadow.set_state(State::from_addr_and_data(Address::AMAX, 500));
+    }
+
+    #[test]
+    fn sweeps_sgt_and_restores() {
+        let mut datagrams = start(VELOCITY_REACHED);
+        for (sgt, results) in [(-1i8, [0, 200]), (0, [400, 420]), (1, [600, 560])] {
+            let mut sweep_coolconf = coolconf();
+            sweep_coolconf.set_semin(0);
+            sweep_coolconf.set_sfilt(true);
+            sweep_coolconf.set_sgt(sgt);
+            datagrams.push(write(Address::COOLCONF, sweep_coolconf.0, 0));
+            for sg_result in results {
+                datagrams.extend(read_register(Address::DRV_STATUS, sg_result));
+            }
+        }
+        datagrams.extend(stop_and_restore());
+        let (mut driver, mut spi) = driver(&datagrams);
+        set_shadow(&mut driver);
+        let sweep = driver.calibrate_sgt(&calibration(), &mut NoopDelay::new()).unwrap();
+        assert_eq!(
+            sweep.entries(),
+            [
+                SgtSample { sgt: -1, mean: 100, min: 0, max: 200 },
+                SgtSample { sgt: 0, mean: 410, min: 400, max: 420 },
+                SgtSample { sgt: 1, mean: 580, min: 560, max: 600 },
+            ]
+        );
+        // -1 reads 0, and 580 lies closer to the centre 500 than 410.
+        assert_eq!(sweep.recommended, Some(1));
+        spi.done();
+    }
+
+    #[test]
+    fn timeout_stops_and_restores() {
+        let mut datagrams = start(0);
+        datagrams.extend(stop_and_restore());
+        let (mut driver, mut spi) = driver(&datagrams);
+        set_shadow(&mut driver);
+        let calibration = SgtCalibration { timeout_ms: 0, ..calibration() };
+        assert!(matches!(driver.calibrate_sgt(&calibration, &mut NoopDelay::new()), Err(Error::Timeout)));
+        spi.done();
+    }
+
+    #[test]
+    fn recommends_nothing_outside_the_window() {
+        let mut sweep = SgtSweep { entries: [SgtSample::default(); SGT_VALUES], len: 2, recommended: None };
+        sweep.entries[0] = SgtSample { sgt: 3, mean: 250, min: 200, max: 300 };
+        sweep.entries[1] = SgtSample { sgt: 4, mean: 750, min: 700, max: 800 };
+        sweep.recommend(300, 700);
+        assert_eq!(sweep.recommended, None);
+        sweep.recommend(200, 700);
+        assert_eq!(sweep.recommended, Some(3));
+    }
+}
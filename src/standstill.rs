@@ -0,0 +1,251 @@
+//! Behaviour of the motor at standstill.
+//!
+//! The driver flags standstill in `DRV_STATUS::stst` 2^20 clock cycles after the last step. After
+//! a further `TPOWERDOWN` times 2^18 clock cycles, it reduces the motor current from `IRUN` to
+//! `IHOLD`, one current step every `IHOLD_IRUN::ihold_delay` times 2^18 clock cycles. With `IHOLD`
+//! at 0 and StealthChop enabled by `GCONF::en_pwm_mode`, `PWMCONF::freewheel` selects whether the
+//! coils are left open or shorted for passive braking.
+//!
+//! [`StandstillConfig`] gives these delays in milliseconds, converted with the clock frequency set
+//! by [`Tmc5130::set_clock_frequency`].
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Error, Tmc5130};
+
+/// Clock cycles per unit of `TPOWERDOWN` and `ihold_delay`.
+const DELAY_UNIT_CLOCKS: u64 = 1 << 18;
+
+/// What the driver does with the coils once the current is reduced.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum StandstillMode {
+    /// Holds the motor with the hold current `IHOLD`.
+    Hold,
+    /// Leaves the coils open so the motor turns freely, `IHOLD` is set to 0.
+    Freewheel,
+    /// Shorts the coils through the low side drivers for passive braking, `IHOLD` is set to 0.
+    BrakeLowSide,
+    /// Shorts the coils through the high side drivers for passive braking, `IHOLD` is set to 0.
+    BrakeHighSide,
+}
+
+impl StandstillMode {
+    fn freewheel(self) -> u8 {
+        match self {
+            StandstillMode::Hold => 0,
+            StandstillMode::Freewheel => 1,
+            StandstillMode::BrakeLowSide => 2,
+            StandstillMode::BrakeHighSide => 3,
+        }
+    }
+}
+
+/// Standstill settings in milliseconds.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StandstillConfig {
+    /// Delay from standstill to the start of the current reduction, up to about 5 seconds.
+    pub power_down_delay_ms: u32,
+    /// Duration of the ramp from the run to the hold current, 0 for an instant reduction.
+    pub current_ramp_ms: u32,
+    /// What happens to the coils at standstill.
+    pub mode: StandstillMode,
+}
+
+impl Default for StandstillConfig {
+    fn default() -> Self {
+        Self {
+            power_down_delay_ms: 200,
+            current_ramp_ms: 100,
+            mode: StandstillMode::Hold,
+        }
+    }
+}
+
+/// The standstill state read from `DRV_STATUS`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StandstillStatus {
+    /// The motor stands still, `DRV_STATUS::stst`.
+    pub standstill: bool,
+    /// The current scale `CS_ACTUAL` has come down to `IHOLD`.
+    pub current_reduced: bool,
+    /// The actual current scale `CS_ACTUAL`.
+    pub current_scale: u8,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Writes `TPOWERDOWN`, `IHOLD_IRUN` and `PWMCONF::freewheel` from a standstill configuration.
+    ///
+    /// The current ramp is spread over the steps between the last known `IRUN` and `IHOLD`, so set
+    /// the currents first. Fails with [`Error::OutOfRange`] if a delay does not fit its register,
+    /// and with [`Error::Unsupported`] if freewheeling or braking is requested while StealthChop
+    /// is disabled in `GCONF`, as `PWMCONF::freewheel` only applies in StealthChop. Returns the
+    /// configuration as it results from the rounded register values.
+    pub fn set_standstill(&mut self, config: &StandstillConfig) -> Result<StandstillConfig, Error<SPI::Error>> {
+        let (_, gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        if config.mode != StandstillMode::Hold && !gconf.en_pwm_mode() {
+            return Err(Error::Unsupported);
+        }
+        let mut ihold_irun = *self.shadow.ihold_irun();
+        if config.mode != StandstillMode::Hold {
+            ihold_irun.set_ihold(0);
+        }
+        let steps = ihold_irun.irun().saturating_sub(ihold_irun.ihold()).max(1) as u32;
+
+        let tpowerdown = self.ms_to_delay_units(config.power_down_delay_ms, 1);
+        let tpowerdown = u8::try_from(tpowerdown).map_err(|_| Error::OutOfRange)?;
+        let mut ihold_delay = self.ms_to_delay_units(config.current_ramp_ms, steps);
+        if config.current_ramp_ms > 0 {
+            ihold_delay = ihold_delay.max(1);
+        }
+        if ihold_delay > 15 {
+            return Err(Error::OutOfRange);
+        }
+        ihold_irun.set_ihold_delay(ihold_delay as u8);
+
+        let mut pwmconf = *self.shadow.pwmconf();
+        pwmconf.set_freewheel(config.mode.freewheel());
+        let mut power_down = reg::TPOWERDOWN::default();
+        power_down.set(tpowerdown);
+
+        self.write_register(power_down)?;
+        self.write_register(pwmconf)?;
+        self.write_register(ihold_irun)?;
+        Ok(self.standstill())
+    }
+
+    /// The standstill configuration of the last known register values.
+    ///
+    /// Freewheeling and braking are only reported with StealthChop enabled in `GCONF`.
+    pub fn standstill(&self) -> StandstillConfig {
+        let ihold_irun = self.shadow.ihold_irun();
+        let steps = ihold_irun.irun().saturating_sub(ihold_irun.ihold()).max(1) as u32;
+        let stealthchop = self.shadow.gconf().en_pwm_mode();
+        let mode = match (stealthchop, ihold_irun.ihold(), self.shadow.pwmconf().freewheel()) {
+            (true, 0, 1) => StandstillMode::Freewheel,
+            (true, 0, 2) => StandstillMode::BrakeLowSide,
+            (true, 0, 3) => StandstillMode::BrakeHighSide,
+            _ => StandstillMode::Hold,
+        };
+        StandstillConfig {
+            power_down_delay_ms: self.delay_units_to_ms(self.shadow.tpowerdown().get() as u32),
+            current_ramp_ms: self.delay_units_to_ms(ihold_irun.ihold_delay() as u32 * steps),
+            mode,
+        }
+    }
+
+    /// Reads `DRV_STATUS` to check whether the motor stands still with the reduced current.
+    pub fn standstill_status(&mut self) -> Result<StandstillStatus, Error<SPI::Error>> {
+        let (_, drv_status) = self.read_register::<reg::DRV_STATUS>().map_err(Error::Spi)?;
+        let current_scale = drv_status.cs_actual() as u8;
+        Ok(StandstillStatus {
+            standstill: drv_status.stst(),
+            current_reduced: drv_status.stst() && current_scale <= self.shadow.ihold_irun().ihold(),
+            current_scale,
+        })
+    }
+
+    /// Converts milliseconds to units of 2^18 clock cycles, divided by `divisor` and rounded.
+    fn ms_to_delay_units(&self, ms: u32, divisor: u32) -> u32 {
+        let clocks = ms as u64 * self.clock_hz as u64 / 1000;
+        let unit = DELAY_UNIT_CLOCKS * divisor as u64;
+        ((clocks + unit / 2) / unit).min(u32::MAX as u64) as u32
+    }
+
+    /// Converts units of 2^18 clock cycles to milliseconds.
+    fn delay_units_to_ms(&self, units: u32) -> u32 {
+        (units as u64 * DELAY_UNIT_CLOCKS * 1000 / self.clock_hz.max(1) as u64) as u32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write};
+    use crate::reg::{Address, State};
+
+    /// A clock at which one delay unit takes 20ms.
+    const CLOCK_HZ: u32 = 50 << 18;
+    /// `PWMCONF` after reset.
+    const PWMCONF: u32 = 0xC10D_0024;
+    const EN_PWM_MODE: u32 = 1 << 2;
+
+    #[test]
+    fn converts_delays_and_spreads_the_ramp_over_the_current_steps() {
+        let mut datagrams = read_register(Address::GCONF, 0).to_vec();
+        datagrams.extend([
+            write(Address::TPOWERDOWN, 10, 0),
+            write(Address::PWMCONF, PWMCONF, 0),
+            // 15 steps from `IRUN` 31 down to `IHOLD` 16, 2 units apart.
+            write(Address::IHOLD_IRUN, 2 << 16 | 31 << 8 | 16, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_clock_frequency(CLOCK_HZ);
+        driver.shadow.set_state(State::from_addr_and_data(Address::IHOLD_IRUN, 31 << 8 | 16));
+        let config = StandstillConfig { power_down_delay_ms: 200, current_ramp_ms: 590, mode: StandstillMode::Hold };
+        let set = driver.set_standstill(&config).unwrap();
+        assert_eq!(set, StandstillConfig { current_ramp_ms: 600, ..config });
+        spi.done();
+    }
+
+    #[test]
+    fn short_ramps_take_at_least_one_unit() {
+        let mut datagrams = read_register(Address::GCONF, 0).to_vec();
+        datagrams.extend([
+            write(Address::TPOWERDOWN, 0, 0),
+            write(Address::PWMCONF, PWMCONF, 0),
+            write(Address::IHOLD_IRUN, 1 << 16 | 31 << 8 | 16, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_clock_frequency(CLOCK_HZ);
+        driver.shadow.set_state(State::from_addr_and_data(Address::IHOLD_IRUN, 31 << 8 | 16));
+        let set = driver.set_standstill(&StandstillConfig { power_down_delay_ms: 0, current_ramp_ms: 1, mode: StandstillMode::Hold }).unwrap();
+        assert_eq!(set.current_ramp_ms, 300);
+        spi.done();
+    }
+
+    #[test]
+    fn freewheel_clears_ihold() {
+        let mut datagrams = read_register(Address::GCONF, EN_PWM_MODE).to_vec();
+        datagrams.extend([
+            write(Address::TPOWERDOWN, 10, 0),
+            write(Address::PWMCONF, PWMCONF | 1 << 20, 0),
+            write(Address::IHOLD_IRUN, 31 << 8, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_clock_frequency(CLOCK_HZ);
+        driver.shadow.set_state(State::from_addr_and_data(Address::IHOLD_IRUN, 31 << 8 | 16));
+        let config = StandstillConfig { power_down_delay_ms: 200, current_ramp_ms: 0, mode: StandstillMode::Freewheel };
+        assert_eq!(driver.set_standstill(&config).unwrap(), config);
+        spi.done();
+    }
+
+    #[test]
+    fn freewheel_requires_stealthchop() {
+        let (mut driver, mut spi) = driver(&read_register(Address::GCONF, 0));
+        let config = StandstillConfig { mode: StandstillMode::BrakeLowSide, ..Default::default() };
+        assert!(matches!(driver.set_standstill(&config), Err(Error::Unsupported)));
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_delays_beyond_the_registers() {
+        let mut datagrams = read_register(Address::GCONF, 0).to_vec();
+        datagrams.extend(read_register(Address::GCONF, 0));
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.set_clock_frequency(CLOCK_HZ);
+        // 256 units.
+        let config = StandstillConfig { power_down_delay_ms: 5_120, ..Default::default() };
+        assert!(matches!(driver.set_standstill(&config), Err(Error::OutOfRange)));
+        // 16 units for the single step from `IRUN` 31 to `IHOLD` 30.
+        driver.shadow.set_state(State::from_addr_and_data(Address::IHOLD_IRUN, 31 << 8 | 30));
+        let config = StandstillConfig { current_ramp_ms: 320, ..Default::default() };
+        assert!(matches!(driver.set_standstill(&config), Err(Error::OutOfRange)));
+        spi.done();
+    }
+}
@@ -0,0 +1,259 @@
+//! Guided automatic tuning of StealthChop.
+//!
+//! With `PWMCONF::pwm_autoscale` set, StealthChop regulates the PWM amplitude `PWM_SCALE` to reach
+//! the run current. The regulation has to learn the motor in two steps before it works well:
+//!
+//! * AT#1: the motor stands still with the run current for at least 130ms, so the amplitude
+//!   matching the coil resistance is found.
+//! * AT#2: the motor moves at a medium velocity until `PWM_SCALE` settles, so the amplitude
+//!   matching the back EMF is found.
+//!
+//! The tuning procedure runs both steps, checks that `PWM_SCALE` converged and returns the tuned
+//! `PWMCONF` for storage, so it can be written directly on the next start.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::spi::SpiDevice;
+
+use crate::{reg, Direction, Error, Tmc5130};
+
+/// The shortest standstill for AT#1 given by the datasheet.
+const MIN_STANDSTILL_MS: u32 = 130;
+
+/// Parameters of the StealthChop tuning procedure.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct StealthChopTuning {
+    /// Regulation loop gradient `PWMCONF::pwm_grad`, 1 to 15.
+    pub pwm_grad: u8,
+    /// PWM frequency `PWMCONF::pwm_freq`, 0 to 3.
+    pub pwm_freq: u8,
+    /// Standstill time for AT#1, at least 130ms.
+    pub standstill_ms: u32,
+    /// Medium velocity for AT#2, written to `VMAX`.
+    pub velocity: u32,
+    /// Acceleration for AT#2, written to `AMAX`.
+    pub acceleration: u16,
+    /// Direction of the AT#2 move.
+    pub direction: Direction,
+    /// Margin added to the settled `PWM_SCALE` to give the tuned `pwm_ampl`.
+    pub ampl_margin: u8,
+    /// Largest change of `PWM_SCALE` between samples still considered settled.
+    pub tolerance: u8,
+    /// Number of consecutive settled samples required for convergence.
+    pub settled_samples: u8,
+    /// Interval between two samples of `PWM_SCALE`, in milliseconds.
+    pub sample_interval_ms: u32,
+    /// Time allowed for `PWM_SCALE` to settle during AT#2, in milliseconds.
+    pub timeout_ms: u32,
+}
+
+impl Default for StealthChopTuning {
+    fn default() -> Self {
+        Self {
+            pwm_grad: 4,
+            pwm_freq: 1,
+            standstill_ms: 200,
+            velocity: 100_000,
+            acceleration: 1_000,
+            direction: Direction::Positive,
+            ampl_margin: 16,
+            tolerance: 2,
+            settled_samples: 8,
+            sample_interval_ms: 10,
+            timeout_ms: 5_000,
+        }
+    }
+}
+
+/// The result of a successful StealthChop tuning.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StealthChopTuned {
+    /// The tuned `PWMCONF`, with `pwm_autoscale` set.
+    pub pwmconf: reg::PWMCONF,
+    /// `PWM_SCALE` at the end of AT#1.
+    pub standstill_scale: u8,
+    /// The settled `PWM_SCALE` of AT#2.
+    pub velocity_scale: u8,
+    /// Whether `PWM_SCALE` reached its maximum, meaning the supply voltage is too low to drive
+    /// the run current at the tuning velocity.
+    pub saturated: bool,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Runs the StealthChop automatic tuning, AT#1 followed by AT#2.
+    ///
+    /// The motor has to be at standstill, and moves in velocity mode during AT#2. The tuned
+    /// `PWMCONF` is left written, while `GCONF`, `IHOLD_IRUN`, `TPWMTHRS`, `VMAX` and `AMAX` are
+    /// restored. Fails with [`Error::NotConverged`] if `PWM_SCALE` does not settle in time, in which
+    /// case `PWMCONF` is restored as well, as on any other error. Fails with [`Error::OutOfRange`]
+    /// if `pwm_grad` or `pwm_freq` is outside its range.
+    pub fn tune_stealthchop<D>(&mut self, tuning: &StealthChopTuning, delay: &mut D) -> Result<StealthChopTuned, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        if !(1..=15).contains(&tuning.pwm_grad) || tuning.pwm_freq > 3 {
+            return Err(Error::OutOfRange);
+        }
+        let (_, ramp_stat) = self.read_register::<reg::RAMP_STAT>().map_err(Error::Spi)?;
+        if !ramp_stat.vzero() {
+            return Err(Error::NotAtStandstill);
+        }
+        let (_, gconf) = self.read_register::<reg::GCONF>().map_err(Error::Spi)?;
+        let ihold_irun = *self.shadow.ihold_irun();
+        let tpwmthrs = *self.shadow.tpwmthrs();
+        let vmax = *self.shadow.vmax();
+        let amax = *self.shadow.amax();
+        let pwmconf = *self.shadow.pwmconf();
+
+        let result = self.tune_stealthchop_inner(tuning, gconf, ihold_irun, delay);
+
+        let restored = self.write_register(amax)
+            .and_then(|_| self.write_register(vmax))
+            .and_then(|_| self.write_register(tpwmthrs))
+            .and_then(|_| self.write_register(ihold_irun))
+            .and_then(|_| self.write_register(gconf));
+        let restored = match result {
+            Ok(_) => restored,
+            Err(_) => restored.and_then(|_| self.write_register(pwmconf)),
+        };
+        result.and_then(|tuned| restored.map(|_| tuned))
+    }
+
+    fn tune_stealthchop_inner<D>(&mut self, tuning: &StealthChopTuning, gconf: reg::GCONF, ihold_irun: reg::IHOLD_IRUN, delay: &mut D) -> Result<StealthChopTuned, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let mut pwmconf = *self.shadow.pwmconf();
+        pwmconf.set_pwm_autoscale(true);
+        pwmconf.set_pwm_grad(tuning.pwm_grad);
+        pwmconf.set_pwm_freq(tuning.pwm_freq);
+        pwmconf.set_pwm_ampl(u8::MAX);
+        self.write_register(pwmconf)?;
+
+        // StealthChop at all velocities.
+        self.write_register(reg::TPWMTHRS::default())?;
+        let mut tuning_gconf = gconf;
+        tuning_gconf.set_en_pwm_mode(true);
+        self.write_register(tuning_gconf)?;
+
+        // AT#1: standstill with the run current.
+        let mut run_current = ihold_irun;
+        run_current.set_ihold(ihold_irun.irun());
+        self.write_register(run_current)?;
+        delay.delay_ms(tuning.standstill_ms.max(MIN_STANDSTILL_MS));
+        let (_, standstill_scale) = self.read_register::<reg::PWM_SCALE>().map_err(Error::Spi)?;
+
+        // AT#2: medium velocity until PWM_SCALE settles.
+        let mut amax = reg::AMAX::default();
+        amax.set(tuning.acceleration);
+        self.write_register(amax)?;
+        let mut vmax = reg::VMAX::default();
+        vmax.set(tuning.velocity);
+        self.write_register(vmax)?;
+        self.write_register(tuning.direction.velocity_mode())?;
+        let poll_interval_us = tuning.sample_interval_ms * 1000;
+        let reached = self.poll_ramp_stat(delay, poll_interval_us, tuning.timeout_ms, |ramp_stat| ramp_stat.velocity_reached());
+        let settled = match reached {
+            Ok(Some(_)) => self.wait_pwm_scale_settled(tuning, delay),
+            Ok(None) => Err(Error::Timeout),
+            Err(error) => Err(error),
+        };
+        self.stop(delay, poll_interval_us, tuning.timeout_ms)?;
+        let velocity_scale = settled?;
+
+        pwmconf.set_pwm_ampl(velocity_scale.saturating_add(tuning.ampl_margin));
+        self.write_register(pwmconf)?;
+        Ok(StealthChopTuned {
+            pwmconf,
+            standstill_scale: standstill_scale.get(),
+            velocity_scale,
+            saturated: velocity_scale == u8::MAX,
+        })
+    }
+
+    /// Samples `PWM_SCALE` until it stays within the tolerance for the required number of samples.
+    fn wait_pwm_scale_settled<D>(&mut self, tuning: &StealthChopTuning, delay: &mut D) -> Result<u8, Error<SPI::Error>>
+    where D: DelayNs
+    {
+        let (_, first) = self.read_register::<reg::PWM_SCALE>().map_err(Error::Spi)?;
+        let mut previous = first.get();
+        let mut settled = 0;
+        let mut elapsed_ms = 0;
+        while elapsed_ms < tuning.timeout_ms {
+            delay.delay_ms(tuning.sample_interval_ms);
+            elapsed_ms += tuning.sample_interval_ms.max(1);
+            let (_, scale) = self.read_register::<reg::PWM_SCALE>().map_err(Error::Spi)?;
+            if scale.get().abs_diff(previous) <= tuning.tolerance {
+                settled += 1;
+                if settled >= tuning.settled_samples {
+                    return Ok(scale.get());
+                }
+            } else {
+                settled = 0;
+            }
+            previous = scale.get();
+        }
+        Err(Error::NotConverged)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use embedded_hal_mock::eh1::delay::NoopDelay;
+
+    use super::*;
+    use crate::mock_peripherals::{driver, read_register, write, VZERO};
+    use crate::reg::Address;
+
+    const PWMCONF: u32 = 0x0005_0480;
+
+    #[test]
+    fn failed_tuning_restores_pwmconf() {
+        let tuning = StealthChopTuning { timeout_ms: 0, ..StealthChopTuning::default() };
+        let mut pwmconf = reg::PWMCONF(PWMCONF);
+        pwmconf.set_pwm_grad(tuning.pwm_grad);
+        pwmconf.set_pwm_freq(tuning.pwm_freq);
+        pwmconf.set_pwm_ampl(u8::MAX);
+        let mut datagrams = read_register(Address::RAMP_STAT, VZERO).to_vec();
+        datagrams.extend(read_register(Address::GCONF, 0));
+        datagrams.extend([
+            write(Address::PWMCONF, pwmconf.0, 0),
+            write(Address::TPWMTHRS, 0, 0),
+            write(Address::GCONF, 0b100, 0),
+            // The default run current of 31 also at standstill.
+            write(Address::IHOLD_IRUN, 0x1F1F, 0),
+        ]);
+        datagrams.extend(read_register(Address::PWM_SCALE, 0x20));
+        datagrams.extend([write(Address::AMAX, 1_000, 0), write(Address::VMAX, 100_000, 0), write(Address::RAMPMODE, 1, 0)]);
+        // The velocity is not reached in time.
+        datagrams.extend(read_register(Address::RAMP_STAT, 0));
+        datagrams.push(write(Address::VMAX, 0, 0));
+        datagrams.extend(read_register(Address::RAMP_STAT, VZERO));
+        datagrams.extend(read_register(Address::XACTUAL, 0));
+        datagrams.extend([write(Address::XTARGET, 0, 0), write(Address::RAMPMODE, 0, 0), write(Address::VMAX, 100_000, 0)]);
+        datagrams.extend([
+            write(Address::AMAX, 0, 0),
+            write(Address::VMAX, 0, 0),
+            write(Address::TPWMTHRS, 0, 0),
+            write(Address::IHOLD_IRUN, 0x1F00, 0),
+            write(Address::GCONF, 0, 0),
+            write(Address::PWMCONF, PWMCONF, 0),
+        ]);
+        let (mut driver, mut spi) = driver(&datagrams);
+        driver.shadow.set_state(reg::PWMCONF(PWMCONF).into());
+        assert!(matches!(driver.tune_stealthchop(&tuning, &mut NoopDelay::new()), Err(Error::Timeout)));
+        assert_eq!(driver.shadow().pwmconf().0, PWMCONF);
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_gradient_outside_range() {
+        for pwm_grad in [0, 16] {
+            let (mut driver, mut spi) = driver(&[]);
+            let tuning = StealthChopTuning { pwm_grad, ..StealthChopTuning::default() };
+            assert!(matches!(driver.tune_stealthchop(&tuning, &mut NoopDelay::new()), Err(Error::OutOfRange)));
+            spi.done();
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Motion through the STEP and DIR inputs, for chips strapped to external step/dir mode.
+//!
+//! With the SD_MODE pin high, the internal ramp generator is disabled and the motor only moves on
+//! pulses of the STEP input, see `IOIN::sd_mode`. [`StepDir`] generates these pulses in software
+//! from the same [`RampConfig`] the internal ramp generator uses, so a motion profile behaves the
+//! same in both modes. The [`Tmc5130`] instance keeps using SPI for configuration and diagnostics.
+
+use embedded_hal::delay::DelayNs;
+use embedded_hal::digital::OutputPin;
+use embedded_hal::spi::SpiDevice;
+
+use crate::ramp::{acceleration_to_hz_per_s, velocity_to_hz, RampConfig};
+use crate::{math, reg, Direction, Error, Tmc5130};
+
+/// A software step generator driving the STEP, DIR and DRV_ENN pins.
+pub struct StepDir<STEP, DIR, EN, D> {
+    step: STEP,
+    dir: DIR,
+    enable: EN,
+    delay: D,
+    /// The clock frequency used to convert the ramp parameters.
+    clock_hz: u32,
+    /// The current position in microsteps, counted by the generator.
+    position: i32,
+    /// STEP high time, and DIR setup time before the first pulse, in nanoseconds.
+    pulse_width_ns: u32,
+}
+
+impl<STEP, DIR, EN, D, E> StepDir<STEP, DIR, EN, D>
+where
+    STEP: OutputPin<Error = E>,
+    DIR: OutputPin<Error = E>,
+    EN: OutputPin<Error = E>,
+    D: DelayNs,
+{
+    /// Creates a step generator, with the driver disabled.
+    ///
+    /// `clock_hz` is the clock frequency the ramp parameters refer to, normally the one of the chip.
+    pub fn new(step: STEP, dir: DIR, enable: EN, delay: D, clock_hz: u32) -> Result<Self, E> {
+        let mut instance = Self {
+            step,
+            dir,
+            enable,
+            delay,
+            clock_hz,
+            position: 0,
+            pulse_width_ns: 1_000,
+        };
+        instance.step.set_low()?;
+        instance.disable()?;
+        Ok(instance)
+    }
+
+    /// Sets the STEP high time and DIR setup time, 1µs by default.
+    pub fn set_pulse_width_ns(&mut self, pulse_width_ns: u32) {
+        self.pulse_width_ns = pulse_width_ns;
+    }
+
+    /// Enables the motor driver, DRV_ENN is active low.
+    pub fn enable(&mut self) -> Result<(), E> {
+        self.enable.set_low()
+    }
+
+    /// Disables the motor driver and lets the motor run free.
+    pub fn disable(&mut self) -> Result<(), E> {
+        self.enable.set_high()
+    }
+
+    /// The current position in microsteps.
+    pub fn position(&self) -> i32 {
+        self.position
+    }
+
+    /// Redefines the current position without moving.
+    pub fn set_position(&mut self, position: i32) {
+        self.position = position;
+    }
+
+    /// Moves `distance` microsteps relative to the current position.
+    pub fn move_by(&mut self, distance: i32, ramp: &RampConfig) -> Result<(), E> {
+        self.move_to(self.position.wrapping_add(distance), ramp)
+    }
+
+    /// Moves to `target` following `ramp`, blocking until the last step is output.
+    ///
+    /// The ramp starts at `VSTART` and ends at `VSTOP`. A move too short to reach `VMAX` turns into
+    /// a triangular profile, as it would with the internal ramp generator. The `A1` and `D1` phases
+    /// are skipped if either of them is zero, as they are with a `V1` of zero.
+    pub fn move_to(&mut self, target: i32, ramp: &RampConfig) -> Result<(), E> {
+        let distance = target.wrapping_sub(self.position);
+        if distance == 0 {
+            return Ok(());
+        }
+        let direction = if distance > 0 { Direction::Positive } else { Direction::Negative };
+        match direction {
+            Direction::Positive => self.dir.set_high()?,
+            Direction::Negative => self.dir.set_low()?,
+        }
+        self.delay.delay_ns(self.pulse_width_ns);
+
+        let profile = Profile::new(ramp, self.clock_hz);
+        let steps = distance.unsigned_abs();
+        let mut velocity = profile.first_velocity();
+        for step in 0..steps {
+            self.step.set_high()?;
+            self.delay.delay_ns(self.pulse_width_ns);
+            self.step.set_low()?;
+            self.position = self.position.wrapping_add(direction.signum());
+
+            let period_ns = (1.0e9 / velocity) as u32;
+            self.delay.delay_ns(period_ns.saturating_sub(self.pulse_width_ns));
+
+            let remaining = (steps - step - 1) as f32;
+            velocity = profile.next_velocity(velocity, remaining);
+        }
+        Ok(())
+    }
+}
+
+/// The ramp converted to microsteps per second and microsteps per second squared.
+struct Profile {
+    vstart: f32,
+    a1: f32,
+    v1: f32,
+    amax: f32,
+    vmax: f32,
+    dmax: f32,
+    d1: f32,
+    vstop: f32,
+}
+
+impl Profile {
+    /// The lowest velocity used, which keeps the step period finite.
+    const MIN_VELOCITY: f32 = 1.0;
+
+    fn new(ramp: &RampConfig, clock_hz: u32) -> Self {
+        // Without an acceleration the first phase would never end.
+        let v1 = if ramp.a1 == 0 || ramp.d1 == 0 { 0 } else { ramp.v1 };
+        Self {
+            vstart: velocity_to_hz(ramp.vstart, clock_hz),
+            a1: acceleration_to_hz_per_s(ramp.a1, clock_hz),
+            v1: velocity_to_hz(v1, clock_hz),
+            amax: acceleration_to_hz_per_s(ramp.amax, clock_hz),
+            vmax: velocity_to_hz(ramp.vmax, clock_hz).max(Self::MIN_VELOCITY),
+            dmax: acceleration_to_hz_per_s(ramp.dmax, clock_hz),
+            d1: acceleration_to_hz_per_s(ramp.d1, clock_hz),
+            vstop: velocity_to_hz(ramp.vstop, clock_hz).max(Self::MIN_VELOCITY),
+        }
+    }
+
+    /// The acceleration at the given velocity.
+    fn acceleration(&self, velocity: f32) -> f32 {
+        if velocity < self.v1 { self.a1 } else { self.amax }
+    }
+
+    /// The deceleration at the given velocity.
+    fn deceleration(&self, velocity: f32) -> f32 {
+        if velocity > self.v1 { self.dmax } else { self.d1 }
+    }
+
+    /// The velocity of the first step, reached from standstill if `VSTART` is zero.
+    fn first_velocity(&self) -> f32 {
+        let from_standstill = math::sqrt(2.0 * self.acceleration(0.0));
+        self.vstart.max(from_standstill).max(Self::MIN_VELOCITY).min(self.vmax)
+    }
+
+    /// The number of steps needed to decelerate from `velocity` to `VSTOP`.
+    fn braking_distance(&self, velocity: f32) -> f32 {
+        let phase = |from: f32, to: f32, deceleration: f32| {
+            if from <= to || deceleration <= 0.0 {
+                0.0
+            } else {
+                (from * from - to * to) / (2.0 * deceleration)
+            }
+        };
+        if self.v1 > 0.0 && velocity > self.v1 {
+            phase(velocity, self.v1, self.dmax) + phase(self.v1, self.vstop, self.d1)
+        } else {
+            phase(velocity, self.vstop, self.deceleration(velocity))
+        }
+    }
+
+    /// The velocity for the next step, with `remaining` steps left after the current one.
+    fn next_velocity(&self, velocity: f32, remaining: f32) -> f32 {
+        // One step at velocity v takes 1/v seconds, in which the velocity changes by a/v.
+        if self.braking_distance(velocity) >= remaining {
+            (velocity - self.deceleration(velocity) / velocity).max(self.vstop)
+        } else {
+            (velocity + self.acceleration(velocity) / velocity).min(self.vmax)
+        }
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Whether the chip is strapped for external step/dir mode, read from `IOIN::sd_mode`.
+    ///
+    /// In this mode the internal ramp generator is not available and a [`StepDir`] generator has
+    /// to move the motor.
+    pub fn step_dir_mode(&mut self) -> Result<bool, Error<SPI::Error>> {
+        let (_, ioin) = self.read_register::<reg::IOIN>().map_err(Error::Spi)?;
+        Ok(ioin.sd_mode())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::INTERNAL_CLOCK_HZ;
+
+    #[test]
+    fn zero_a1_skips_first_phase() {
+        let ramp = RampConfig { vstart: 0, a1: 0, v1: 10_000, amax: 1_000, vmax: 100_000, dmax: 1_000, d1: 1_000, vstop: 10 };
+        let profile = Profile::new(&ramp, INTERNAL_CLOCK_HZ);
+        let first = profile.first_velocity();
+        assert!(first > Profile::MIN_VELOCITY);
+        assert!(profile.next_velocity(first, 1_000_000.0) > first);
+    }
+}
@@ -0,0 +1,156 @@
+//! Streaming register reads.
+//!
+//! Every datagram returns the data requested by the previous one, which is why
+//! [`Tmc5130::read_register`] needs two transactions and throws the first reply away. A
+//! [`RegisterStream`] instead sends the next read request with each datagram and returns the reply
+//! to the pending one, so a polling loop costs one datagram per register in steady state.
+//!
+//! The stream borrows the driver mutably, so no other traffic can break the pipeline while it is in
+//! use.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, Address, State};
+use crate::{Error, Tmc5130};
+
+/// A register value returned by a [`RegisterStream`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct StreamReply {
+    /// The status returned with the datagram that carried the value.
+    pub status: reg::SPISTATUS,
+    /// The register value, with backlash compensation applied as by [`Tmc5130::read_register`].
+    pub state: State,
+}
+
+/// A pipelined sequence of register reads, see [`Tmc5130::stream`].
+pub struct RegisterStream<'a, SPI> {
+    driver: &'a mut Tmc5130<SPI>,
+    pending: Option<Address>,
+}
+
+impl<SPI> RegisterStream<'_, SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// The address whose value arrives with the next datagram.
+    pub fn pending(&self) -> Option<Address> {
+        self.pending
+    }
+
+    /// Requests `addr` and returns the value of the pending request, `None` on the first request.
+    ///
+    /// Fails with [`Error::Unsupported`] for write-only registers.
+    pub fn request(&mut self, addr: Address) -> Result<Option<StreamReply>, Error<SPI::Error>> {
+        if !addr.readable() {
+            return Err(Error::Unsupported);
+        }
+        let (status, data) = self.driver.read_raw(addr).map_err(Error::Spi)?;
+        let reply = self.pending.map(|pending| self.reply(pending, status, data));
+        self.pending = Some(addr);
+        Ok(reply)
+    }
+
+    /// Collects the value of the pending request with one more datagram, ending the sequence.
+    pub fn finish(&mut self) -> Result<Option<StreamReply>, Error<SPI::Error>> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(None);
+        };
+        let (status, data) = self.driver.read_raw(pending).map_err(Error::Spi)?;
+        Ok(Some(self.reply(pending, status, data)))
+    }
+
+    /// Requests each of `addresses` in turn, storing the replies in `replies` in the order they
+    /// arrive. Returns the number of replies stored, which lags the requests by one.
+    ///
+    /// Called repeatedly with the same addresses, every call yields one reply per address, the
+    /// first one completing the last request of the previous call. Fails with
+    /// [`Error::OutOfRange`] before sending anything if `replies` is shorter than `addresses`.
+    pub fn cycle(&mut self, addresses: &[Address], replies: &mut [StreamReply]) -> Result<usize, Error<SPI::Error>> {
+        if replies.len() < addresses.len() {
+            return Err(Error::OutOfRange);
+        }
+        let mut count = 0;
+        for &addr in addresses {
+            if let Some(reply) = self.request(addr)? {
+                replies[count] = reply;
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    fn reply(&mut self, addr: Address, status: reg::SPISTATUS, data: u32) -> StreamReply {
+        self.driver.shadow.set_state(State::from_addr_and_data(addr, data));
+        StreamReply { status, state: State::from_addr_and_data(addr, self.driver.backlash_read(addr, data)) }
+    }
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Starts a pipelined sequence of register reads.
+    ///
+    /// The first reply of the stream belongs to whatever was sent before and is discarded.
+    pub fn stream(&mut self) -> RegisterStream<'_, SPI> {
+        RegisterStream { driver: self, pending: None }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, read};
+
+    const POLLED: [Address; 3] = [Address::XACTUAL, Address::RAMP_STAT, Address::DRV_STATUS];
+
+    fn reply(addr: Address, data: u32) -> StreamReply {
+        StreamReply { status: reg::SPISTATUS(0), state: State::from_addr_and_data(addr, data) }
+    }
+
+    #[test]
+    fn tracks_pending_request() {
+        let (mut driver, mut spi) = driver(&[
+            read(Address::XACTUAL, 0xFFFF_FFFF),
+            read(Address::DRV_STATUS, 100),
+            read(Address::DRV_STATUS, 7),
+        ]);
+        let mut stream = driver.stream();
+        assert_eq!(stream.request(Address::XACTUAL).unwrap(), None);
+        assert_eq!(stream.pending(), Some(Address::XACTUAL));
+        assert_eq!(stream.request(Address::DRV_STATUS).unwrap(), Some(reply(Address::XACTUAL, 100)));
+        assert_eq!(stream.finish().unwrap(), Some(reply(Address::DRV_STATUS, 7)));
+        assert_eq!(stream.pending(), None);
+        assert_eq!(stream.finish().unwrap(), None);
+        spi.done();
+    }
+
+    #[test]
+    fn cycles_take_one_datagram_per_register() {
+        let (mut driver, mut spi) = driver(&[
+            read(Address::XACTUAL, 0),
+            read(Address::RAMP_STAT, 1),
+            read(Address::DRV_STATUS, 2),
+            read(Address::XACTUAL, 3),
+            read(Address::RAMP_STAT, 4),
+            read(Address::DRV_STATUS, 5),
+        ]);
+        let mut stream = driver.stream();
+        let mut replies = [reply(Address::XACTUAL, 0); 3];
+        assert_eq!(stream.cycle(&POLLED, &mut replies).unwrap(), 2);
+        assert_eq!(stream.cycle(&POLLED, &mut replies).unwrap(), 3);
+        assert_eq!(replies, [reply(Address::DRV_STATUS, 3), reply(Address::XACTUAL, 4), reply(Address::RAMP_STAT, 5)]);
+        assert_eq!(stream.pending(), Some(Address::DRV_STATUS));
+        spi.done();
+    }
+
+    #[test]
+    fn rejects_short_buffers_and_write_only_registers() {
+        let (mut driver, mut spi) = driver(&[]);
+        let mut stream = driver.stream();
+        let mut replies = [reply(Address::XACTUAL, 0); 2];
+        assert!(matches!(stream.cycle(&POLLED, &mut replies), Err(Error::OutOfRange)));
+        assert!(matches!(stream.request(Address::VMAX), Err(Error::Unsupported)));
+        spi.done();
+    }
+}
@@ -0,0 +1,221 @@
+//! Load and torque telemetry.
+//!
+//! [`Telemetry`] reads `DRV_STATUS`, `PWM_SCALE`, `VACTUAL` and `TSTEP` in one pipelined batch and
+//! keeps the last `N` values of `SG_RESULT`, `CS_ACTUAL` and `PWM_SCALE` in fixed-size windows for
+//! moving averages and extremes.
+//!
+//! `SG_RESULT` falls as the load on the motor rises, so the load is estimated relative to a
+//! baseline measured without load: 0% at the baseline, 100% at an `SG_RESULT` of 0. `SG_RESULT` is
+//! only valid in SpreadCycle above `TCOOLTHRS`, and the baseline only holds for the velocity and
+//! current it was measured at.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::reg::{self, State};
+use crate::{Action, Error, Tmc5130};
+
+/// One telemetry sample.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct TelemetrySample {
+    /// StallGuard2 load measurement `DRV_STATUS::sg_result`.
+    pub sg_result: u16,
+    /// Actual current scale `DRV_STATUS::cs_actual`.
+    pub cs_actual: u8,
+    /// StealthChop amplitude `PWM_SCALE`.
+    pub pwm_scale: u8,
+    /// Actual velocity `VACTUAL`.
+    pub vactual: i32,
+    /// Time between two 1/256 microsteps `TSTEP`.
+    pub tstep: u32,
+}
+
+/// Statistics over a window of values.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct Statistics {
+    /// Moving average.
+    pub mean: u16,
+    /// Lowest value.
+    pub min: u16,
+    /// Highest value.
+    pub max: u16,
+}
+
+/// A ring buffer of the last `N` values.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+struct Window<const N: usize> {
+    values: [u16; N],
+    next: usize,
+    len: usize,
+}
+
+impl<const N: usize> Window<N> {
+    const fn new() -> Self {
+        Self { values: [0; N], next: 0, len: 0 }
+    }
+
+    fn push(&mut self, value: u16) {
+        if N == 0 {
+            return;
+        }
+        self.values[self.next] = value;
+        self.next = (self.next + 1) % N;
+        self.len = (self.len + 1).min(N);
+    }
+
+    fn statistics(&self) -> Option<Statistics> {
+        let values = &self.values[..self.len];
+        let sum: u32 = values.iter().map(|&value| value as u32).sum();
+        Some(Statistics {
+            mean: (sum / values.len().max(1) as u32) as u16,
+            min: *values.iter().min()?,
+            max: *values.iter().max()?,
+        })
+    }
+}
+
+/// Telemetry over the last `N` samples.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Telemetry<const N: usize> {
+    sg_result: Window<N>,
+    cs_actual: Window<N>,
+    pwm_scale: Window<N>,
+    last: Option<TelemetrySample>,
+    baseline: Option<u16>,
+}
+
+impl<const N: usize> Telemetry<N> {
+    /// Telemetry without samples and without a baseline.
+    pub const fn new() -> Self {
+        Self {
+            sg_result: Window::new(),
+            cs_actual: Window::new(),
+            pwm_scale: Window::new(),
+            last: None,
+            baseline: None,
+        }
+    }
+
+    /// Reads a sample from the chip in one batch of five datagrams and adds it to the windows.
+    pub fn sample<SPI>(&mut self, driver: &mut Tmc5130<SPI>) -> Result<TelemetrySample, Error<SPI::Error>>
+    where SPI: SpiDevice<u8>
+    {
+        let mut drv_status: State = reg::DRV_STATUS::default().into();
+        let mut pwm_scale: State = reg::PWM_SCALE::default().into();
+        let mut vactual: State = reg::VACTUAL::default().into();
+        let mut tstep: State = reg::TSTEP::default().into();
+        driver.bulk_register_action(&mut [
+            Action::read(&mut drv_status),
+            Action::read(&mut pwm_scale),
+            Action::read(&mut vactual),
+            Action::read(&mut tstep),
+        ])?;
+
+        let drv_status = reg::DRV_STATUS::try_from(drv_status).unwrap_or_default();
+        let sample = TelemetrySample {
+            sg_result: drv_status.sg_result() as u16,
+            cs_actual: drv_status.cs_actual() as u8,
+            pwm_scale: reg::PWM_SCALE::try_from(pwm_scale).unwrap_or_default().get(),
+            vactual: reg::VACTUAL::try_from(vactual).unwrap_or_default().get(),
+            tstep: reg::TSTEP::try_from(tstep).unwrap_or_default().get(),
+        };
+        self.push(sample);
+        Ok(sample)
+    }
+
+    /// Adds a sample taken elsewhere.
+    pub fn push(&mut self, sample: TelemetrySample) {
+        self.sg_result.push(sample.sg_result);
+        self.cs_actual.push(sample.cs_actual as u16);
+        self.pwm_scale.push(sample.pwm_scale as u16);
+        self.last = Some(sample);
+    }
+
+    /// Drops all samples, keeping the baseline.
+    pub fn reset(&mut self) {
+        *self = Self { baseline: self.baseline, ..Self::new() };
+    }
+
+    /// The last sample.
+    pub fn last(&self) -> Option<TelemetrySample> {
+        self.last
+    }
+
+    /// Statistics of `SG_RESULT`, `None` without samples.
+    pub fn sg_result(&self) -> Option<Statistics> {
+        self.sg_result.statistics()
+    }
+
+    /// Statistics of `CS_ACTUAL`, `None` without samples.
+    pub fn cs_actual(&self) -> Option<Statistics> {
+        self.cs_actual.statistics()
+    }
+
+    /// Statistics of `PWM_SCALE`, `None` without samples.
+    pub fn pwm_scale(&self) -> Option<Statistics> {
+        self.pwm_scale.statistics()
+    }
+
+    /// Takes the average `SG_RESULT` of the window as the no-load baseline, returning it.
+    ///
+    /// Call this after sampling the motor running without load.
+    pub fn calibrate_baseline(&mut self) -> Option<u16> {
+        self.baseline = self.sg_result().map(|statistics| statistics.mean).filter(|&mean| mean > 0);
+        self.baseline
+    }
+
+    /// Sets the no-load baseline of `SG_RESULT`, for instance from an earlier calibration.
+    pub fn set_baseline(&mut self, baseline: Option<u16>) {
+        self.baseline = baseline.filter(|&baseline| baseline > 0);
+    }
+
+    /// The no-load baseline of `SG_RESULT`.
+    pub fn baseline(&self) -> Option<u16> {
+        self.baseline
+    }
+
+    /// The load in percent from the average `SG_RESULT`, 0 to 100, `None` without a baseline or
+    /// samples.
+    pub fn load_percent(&self) -> Option<f32> {
+        let baseline = self.baseline? as f32;
+        let mean = self.sg_result()?.mean as f32;
+        Some(((baseline - mean) / baseline * 100.0).clamp(0.0, 100.0))
+    }
+}
+
+impl<const N: usize> Default for Telemetry<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(sg_result: u16) -> TelemetrySample {
+        TelemetrySample { sg_result, ..Default::default() }
+    }
+
+    #[test]
+    fn window_keeps_last_samples() {
+        let mut telemetry = Telemetry::<3>::new();
+        assert_eq!(telemetry.sg_result(), None);
+        for sg_result in [100, 400, 200, 300] {
+            telemetry.push(sample(sg_result));
+        }
+        assert_eq!(telemetry.sg_result(), Some(Statistics { mean: 300, min: 200, max: 400 }));
+    }
+
+    #[test]
+    fn load_relative_to_baseline() {
+        let mut telemetry = Telemetry::<4>::new();
+        telemetry.push(sample(400));
+        assert_eq!(telemetry.load_percent(), None);
+        assert_eq!(telemetry.calibrate_baseline(), Some(400));
+        telemetry.reset();
+        telemetry.push(sample(100));
+        assert_eq!(telemetry.load_percent(), Some(75.0));
+    }
+}
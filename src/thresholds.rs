@@ -0,0 +1,251 @@
+//! Velocity based mode switching thresholds.
+//!
+//! `TPWMTHRS`, `TCOOLTHRS` and `THIGH` are compared against `TSTEP`, the time between two 1/256
+//! microsteps in clock cycles, so they are inverse to the velocity. As `TSTEP` does not depend on
+//! the microstep resolution, a threshold stands for the same physical speed at any resolution. The
+//! chip switches modes as follows:
+//!
+//! * StealthChop, if enabled by `GCONF::en_pwm_mode`, while `TSTEP >= TPWMTHRS`, so below the
+//!   StealthChop velocity limit. SpreadCycle is used above it.
+//! * CoolStep and the StallGuard2 stall output while `TCOOLTHRS >= TSTEP > THIGH`.
+//! * The high velocity settings `CHOPCONF::vhighfs` and `vhighchm` while `TSTEP <= THIGH`.
+//!
+//! A threshold of 0 disables the respective switching point. This module sets the thresholds from
+//! speeds and reports back where the chip will switch after rounding.
+
+use embedded_hal::spi::SpiDevice;
+
+use crate::ramp::{microsteps, tstep_from_hz, tstep_to_hz, velocity_to_hz};
+use crate::{reg, Error, Tmc5130};
+
+/// A motor speed.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub enum Speed {
+    /// Microsteps per second, at the configured microstep resolution.
+    MicrostepsPerSecond(f32),
+    /// Full steps per second.
+    FullStepsPerSecond(f32),
+    /// Revolutions per minute of a motor with the given number of full steps per revolution.
+    Rpm { rpm: f32, full_steps_per_rev: u16 },
+}
+
+impl Speed {
+    /// The speed in microsteps per second at the given `CHOPCONF::mres`.
+    pub fn microsteps_per_second(self, mres: u32) -> f32 {
+        let microsteps = microsteps(mres) as f32;
+        match self {
+            Speed::MicrostepsPerSecond(hz) => hz,
+            Speed::FullStepsPerSecond(hz) => hz * microsteps,
+            Speed::Rpm { rpm, full_steps_per_rev } => rpm / 60.0 * full_steps_per_rev as f32 * microsteps,
+        }
+    }
+}
+
+/// The switching points of the chopper modes, `None` for a disabled switching point.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct VelocityThresholds {
+    /// Speed above which StealthChop hands over to SpreadCycle, `TPWMTHRS`.
+    pub stealthchop_max: Option<Speed>,
+    /// Speed above which CoolStep and the stall output are active, `TCOOLTHRS`.
+    pub coolstep_min: Option<Speed>,
+    /// Speed above which the high velocity chopper settings apply, `THIGH`.
+    pub high_velocity: Option<Speed>,
+}
+
+/// The switching points the chip actually uses, in microsteps per second.
+///
+/// `None` means the switching point is disabled.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct SwitchingPoints {
+    /// StealthChop is used below this velocity, if enabled.
+    pub stealthchop_max_hz: Option<f32>,
+    /// CoolStep and the stall output are active above this velocity.
+    pub coolstep_min_hz: Option<f32>,
+    /// The high velocity chopper settings apply above this velocity.
+    pub high_velocity_hz: Option<f32>,
+}
+
+/// The modes the chip uses at a given velocity.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[cfg_attr(feature = "defmt", derive(defmt::Format))]
+pub struct ActiveModes {
+    /// `TSTEP` at the velocity.
+    pub tstep: u32,
+    /// StealthChop is active, otherwise the chopper of `CHOPCONF` is.
+    pub stealthchop: bool,
+    /// The velocity lies within the CoolStep and stall output window.
+    pub coolstep_window: bool,
+    /// The velocity lies above `THIGH`.
+    pub high_velocity: bool,
+    /// The motor is driven in fullsteps, due to `vhighfs` above `THIGH`.
+    pub fullstep: bool,
+    /// The constant off time chopper is used, from `chm` or `vhighchm` above `THIGH`.
+    pub constant_off_time: bool,
+}
+
+impl<SPI> Tmc5130<SPI>
+where
+    SPI: SpiDevice<u8>,
+{
+    /// Writes `TPWMTHRS`, `TCOOLTHRS` and `THIGH` from speeds.
+    ///
+    /// Speeds in microsteps per second refer to the microstep resolution of the last known
+    /// `CHOPCONF`. The thresholds written keep their physical speed when the resolution changes
+    /// later. Returns where the chip will switch after rounding.
+    pub fn set_velocity_thresholds(&mut self, thresholds: &VelocityThresholds) -> Result<SwitchingPoints, Error<SPI::Error>> {
+        let mres = self.shadow.chopconf().mres();
+        let tstep = |speed: Option<Speed>| match speed {
+            Some(speed) => tstep_from_hz(speed.microsteps_per_second(mres), mres, self.clock_hz),
+            None => 0,
+        };
+        let mut tpwmthrs = reg::TPWMTHRS::default();
+        tpwmthrs.set(tstep(thresholds.stealthchop_max));
+        let mut tcoolthrs = reg::TCOOLTHRS::default();
+        tcoolthrs.set(tstep(thresholds.coolstep_min));
+        let mut thigh = reg::THIGH::default();
+        thigh.set(tstep(thresholds.high_velocity));
+
+        self.write_register(tpwmthrs)?;
+        self.write_register(tcoolthrs)?;
+        self.write_register(thigh)?;
+        Ok(self.switching_points())
+    }
+
+    /// The switching points of the last known thresholds, in microsteps per second.
+    pub fn switching_points(&self) -> SwitchingPoints {
+        let mres = self.shadow.chopconf().mres();
+        let hz = |tstep: u32| (tstep != 0).then(|| tstep_to_hz(tstep, mres, self.clock_hz));
+        SwitchingPoints {
+            stealthchop_max_hz: hz(self.shadow.tpwmthrs().get()),
+            coolstep_min_hz: hz(self.shadow.tcoolthrs().get()),
+            high_velocity_hz: hz(self.shadow.thigh().get()),
+        }
+    }
+
+    /// The modes the chip uses at the given `VACTUAL`, from the last known register values.
+    pub fn modes_at_velocity(&self, vactual: i32) -> ActiveModes {
+        let chopconf = self.shadow.chopconf();
+        let mres = chopconf.mres();
+        let hz = velocity_to_hz(vactual.unsigned_abs(), self.clock_hz);
+        let tstep = tstep_from_hz(hz, mres, self.clock_hz);
+
+        let thigh = self.shadow.thigh().get();
+        let high_velocity = tstep <= thigh;
+        ActiveModes {
+            tstep,
+            stealthchop: self.shadow.gconf().en_pwm_mode() && tstep >= self.shadow.tpwmthrs().get(),
+            coolstep_window: tstep <= self.shadow.tcoolthrs().get() && tstep > thigh,
+            high_velocity,
+            fullstep: high_velocity && chopconf.vhighfs(),
+            constant_off_time: chopconf.chm() || (high_velocity && chopconf.vhighchm()),
+        }
+    }
+
+    /// Reads `VACTUAL` and reports the modes the chip uses right now.
+    pub fn active_modes(&mut self) -> Result<ActiveModes, Error<SPI::Error>> {
+        let (_, vactual) = self.read_register::<reg::VACTUAL>().map_err(Error::Spi)?;
+        Ok(self.modes_at_velocity(vactual.get()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_peripherals::{driver, write};
+    use crate::reg::{Address, State};
+
+    /// A clock at which a ramp generator velocity equals microsteps per second.
+    const CLOCK_HZ: u32 = 1 << 24;
+
+    #[test]
+    fn speeds_in_microsteps() {
+        assert_eq!(Speed::FullStepsPerSecond(100.0).microsteps_per_second(4), 1_600.0);
+        assert_eq!(Speed::Rpm { rpm: 60.0, full_steps_per_rev: 200 }.microsteps_per_second(0), 51_200.0);
+        assert_eq!(Speed::MicrostepsPerSecond(500.0).microsteps_per_second(8), 500.0);
+    }
+
+    #[test]
+    fn tstep_does_not_depend_on_resolution() {
+        // 500 fullsteps per second are 128000 1/256 microsteps per second.
+        assert_eq!(tstep_from_hz(128_000.0, 0, 16_000_000), 125);
+        assert_eq!(tstep_from_hz(8_000.0, 4, 16_000_000), 125);
+        assert_eq!(tstep_to_hz(125, 4, 16_000_000), 8_000.0);
+        assert_eq!(tstep_from_hz(0.0, 0, 16_000_000), (1 << 20) - 1);
+        assert_eq!(tstep_from_hz(1.0, 0, 16_000_000), (1 << 20) - 1);
+    }
+
+    #[test]
+    fn writes_thresholds_and_reports_switching_points() {
+        let (mut driver, mut spi) = driver(&[
+            write(Address::TPWMTHRS, 125, 0),
+            write(Address::TCOOLTHRS, 500, 0),
+            write(Address::THIGH, 0, 0),
+        ]);
+        driver.set_clock_frequency(16_000_000);
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_mres(4);
+        driver.shadow.set_state(chopconf.into());
+        let points = driver
+            .set_velocity_thresholds(&VelocityThresholds {
+                stealthchop_max: Some(Speed::FullStepsPerSecond(500.0)),
+                coolstep_min: Some(Speed::MicrostepsPerSecond(2_000.0)),
+                high_velocity: None,
+            })
+            .unwrap();
+        assert_eq!(points, SwitchingPoints { stealthchop_max_hz: Some(8_000.0), coolstep_min_hz: Some(2_000.0), high_velocity_hz: None });
+        spi.done();
+    }
+
+    #[test]
+    fn modes_follow_the_thresholds() {
+        let (mut driver, mut spi) = driver(&[]);
+        driver.set_clock_frequency(CLOCK_HZ);
+        let mut gconf = reg::GCONF::default();
+        gconf.set_en_pwm_mode(true);
+        let mut chopconf = reg::CHOPCONF::default();
+        chopconf.set_vhighfs(true);
+        chopconf.set_vhighchm(true);
+        driver.shadow.set_state(gconf.into());
+        driver.shadow.set_state(chopconf.into());
+        driver.shadow.set_state(State::from_addr_and_data(Address::TPWMTHRS, 4_096));
+        driver.shadow.set_state(State::from_addr_and_data(Address::TCOOLTHRS, 8_192));
+        driver.shadow.set_state(State::from_addr_and_data(Address::THIGH, 1_024));
+
+        let modes = driver.modes_at_velocity(0);
+        assert_eq!(modes.tstep, (1 << 20) - 1);
+        assert!(modes.stealthchop && !modes.coolstep_window && !modes.high_velocity);
+
+        // The thresholds are inclusive, and the direction does not matter.
+        let modes = driver.modes_at_velocity(-4_096);
+        assert_eq!(modes.tstep, 4_096);
+        assert!(modes.stealthchop && modes.coolstep_window && !modes.high_velocity);
+
+        let modes = driver.modes_at_velocity(8_192);
+        assert!(!modes.stealthchop && modes.coolstep_window && !modes.high_velocity && !modes.constant_off_time);
+
+        let modes = driver.modes_at_velocity(16_384);
+        assert_eq!(modes.tstep, 1_024);
+        assert_eq!(
+            modes,
+            ActiveModes { tstep: 1_024, stealthchop: false, coolstep_window: false, high_velocity: true, fullstep: true, constant_off_time: true }
+        );
+        spi.done();
+    }
+
+    #[test]
+    fn disabled_thresholds() {
+        let (mut driver, mut spi) = driver(&[]);
+        driver.set_clock_frequency(CLOCK_HZ);
+        let mut gconf = reg::GCONF::default();
+        gconf.set_en_pwm_mode(true);
+        driver.shadow.set_state(gconf.into());
+        // StealthChop at any velocity, no CoolStep window and no high velocity range.
+        let modes = driver.modes_at_velocity(1 << 20);
+        assert!(modes.stealthchop && !modes.coolstep_window && !modes.high_velocity);
+        assert_eq!(driver.switching_points(), SwitchingPoints::default());
+        spi.done();
+    }
+}